@@ -0,0 +1,127 @@
+//! Redirects the printing functions of `std.io` into a buffer owned by the running evaluation so
+//! that the output can be returned to the user instead of ending up in the server's stdout. Items
+//! emitted through `gluon.try.display` are collected here as well and share the output limit.
+
+use std::cell::RefCell;
+
+use try_gluon_api::{DisplayItem, OutputChunk, OutputSink, Stream};
+
+use gluon::{
+    vm::{self, api::IO, primitive, record, ExternModule},
+    Thread,
+};
+
+thread_local! {
+    static CAPTURE: RefCell<Option<Capture>> = RefCell::new(None);
+}
+
+#[derive(Default)]
+pub(crate) struct Output {
+    pub chunks: Vec<OutputChunk>,
+    pub display: Vec<DisplayItem>,
+    pub truncated: bool,
+}
+
+struct Capture {
+    output: Output,
+    remaining: usize,
+    on_output: OutputSink,
+}
+
+impl Capture {
+    fn write(&mut self, stream: Stream, text: &str) {
+        if text.len() > self.remaining {
+            self.output.truncated = true;
+        }
+        let mut end = text.len().min(self.remaining);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let text = &text[..end];
+        if text.is_empty() {
+            return;
+        }
+        self.remaining -= text.len();
+        (self.on_output)(&OutputChunk {
+            stream,
+            text: text.into(),
+        });
+
+        // Merge consecutive writes to the same stream to keep the output compact
+        match self.output.chunks.last_mut() {
+            Some(last) if last.stream == stream => last.text.push_str(text),
+            _ => self.output.chunks.push(OutputChunk {
+                stream,
+                text: text.into(),
+            }),
+        }
+    }
+
+    fn display(&mut self, item: DisplayItem) {
+        // Cutting an item short would leave it unreadable so it is discarded as a whole
+        if item.data.len() > self.remaining {
+            self.output.truncated = true;
+            return;
+        }
+        self.remaining -= item.data.len();
+        self.output.display.push(item);
+    }
+}
+
+/// Runs `f`, capturing at most `limit` bytes of everything the sandbox prints on this thread.
+/// The captured output is passed to `on_output` as well, as it is printed.
+pub(crate) fn capture<R>(
+    limit: usize,
+    on_output: OutputSink,
+    f: impl FnOnce() -> R,
+) -> (R, Output) {
+    CAPTURE.with(|capture| {
+        *capture.borrow_mut() = Some(Capture {
+            output: Output::default(),
+            remaining: limit,
+            on_output,
+        })
+    });
+    let result = f();
+    let output = CAPTURE
+        .with(|capture| capture.borrow_mut().take())
+        .map(|capture| capture.output)
+        .unwrap_or_default();
+    (result, output)
+}
+
+fn write(stream: Stream, text: &str) -> IO<()> {
+    // Output produced outside of `capture` (while loading the prelude for instance) is discarded
+    CAPTURE.with(|capture| {
+        if let Some(capture) = &mut *capture.borrow_mut() {
+            capture.write(stream, text);
+        }
+    });
+    IO::Value(())
+}
+
+fn write_line(stream: Stream, text: &str) -> IO<()> {
+    // A single write so that a streamed line is not split into two chunks
+    write(stream, &format!("{}\n", text))
+}
+
+/// Adds `item` to the display items of the running evaluation.
+pub(crate) fn display(item: DisplayItem) {
+    CAPTURE.with(|capture| {
+        if let Some(capture) = &mut *capture.borrow_mut() {
+            capture.display(item);
+        }
+    });
+}
+
+pub(crate) fn load(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            print => primitive!(1, "try.io.print", |s: &str| write(Stream::Stdout, s)),
+            println => primitive!(1, "try.io.println", |s: &str| write_line(Stream::Stdout, s)),
+            eprint => primitive!(1, "try.io.eprint", |s: &str| write(Stream::Stderr, s)),
+            eprintln => primitive!(1, "try.io.eprintln", |s: &str| write_line(Stream::Stderr, s))
+        },
+    )
+}
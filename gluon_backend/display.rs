@@ -0,0 +1,125 @@
+//! `gluon.try.display`, which lets snippets emit rich output such as HTML, SVG, Markdown or tables
+//! to be displayed alongside the value. The items are collected by `capture`.
+
+use try_gluon_api::{DisplayItem, TABLE_MIME};
+
+use gluon::{
+    vm::{self, api::IO, primitive, record, ExternModule},
+    Thread,
+};
+
+use crate::capture;
+
+fn mime(mime: &str, data: &str) -> IO<()> {
+    capture::display(DisplayItem {
+        mime: mime.into(),
+        data: data.into(),
+    });
+    IO::Value(())
+}
+
+fn table(columns: Vec<String>, rows: Vec<Vec<String>>) -> IO<()> {
+    if let Some((index, row)) = rows
+        .iter()
+        .enumerate()
+        .find(|(_, row)| row.len() != columns.len())
+    {
+        return IO::Exception(format!(
+            "Row {} has {} cells but the table has {} columns",
+            index,
+            row.len(),
+            columns.len()
+        ));
+    }
+    let data = serde_json::json!({ "columns": columns, "rows": rows });
+    mime(TABLE_MIME, &data.to_string())
+}
+
+pub(crate) fn load(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            mime => primitive!(2, "gluon.try.display.mime", mime),
+            html => primitive!(1, "gluon.try.display.html", |s: &str| mime("text/html", s)),
+            svg => primitive!(1, "gluon.try.display.svg", |s: &str| mime("image/svg+xml", s)),
+            markdown => {
+                primitive!(1, "gluon.try.display.markdown", |s: &str| mime("text/markdown", s))
+            },
+            table => primitive!(2, "gluon.try.display.table", table)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::{Limits, Phase};
+
+    #[test]
+    fn display_items() {
+        let result = crate::test_eval_source(
+            r##"
+            let display = import! gluon.try.display
+            display.markdown "# Title"
+            "##,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_eq!(
+            result.display,
+            [DisplayItem {
+                mime: "text/markdown".into(),
+                data: "# Title".into(),
+            }],
+            "{:?}",
+            result
+        );
+
+        let result = crate::test_eval_source(
+            r#"
+            let display = import! gluon.try.display
+            display.table ["name", "age"] [["Ada", "36"]]
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_eq!(
+            result.display,
+            [DisplayItem {
+                mime: TABLE_MIME.into(),
+                data: r#"{"columns":["name","age"],"rows":[["Ada","36"]]}"#.into(),
+            }],
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn invalid_and_oversized_items() {
+        let result = crate::test_eval_source(
+            r#"
+            let display = import! gluon.try.display
+            display.table ["name", "age"] [["Ada"]]
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_eq!(result.phase, Some(Phase::Runtime), "{:?}", result);
+
+        let limits = Limits {
+            output: 4,
+            ..Limits::default()
+        };
+        let result = crate::test_eval_source(
+            r#"
+            let display = import! gluon.try.display
+            display.html "<b>Too long</b>"
+            "#,
+            &limits,
+            |_| (),
+        );
+        assert!(result.display.is_empty(), "{:?}", result);
+        assert!(result.output_truncated);
+    }
+}
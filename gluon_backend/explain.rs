@@ -0,0 +1,188 @@
+//! Shows what the compiler makes of a snippet: the source after macro expansion, the inferred type
+//! of each binding and the bytecode of the top-level function.
+
+use try_gluon_api::{ExplainRequest, ExplainView, Explanation, TypedBinding};
+
+use gluon::{
+    base::{
+        ast::{self, Argument, Expr, Pattern, SpannedExpr, SpannedIdent, SpannedPattern, Visitor},
+        pos::{BytePos, Span},
+        symbol::Symbol,
+        types::ArcType,
+    },
+    compiler_pipeline::{Compileable, MacroExpandable, Typecheckable},
+    vm::compiler::CompiledFunction,
+    Error, Thread, ThreadExt,
+};
+
+use crate::{EvalVm, TopName};
+
+pub fn explain(vm: &EvalVm, request: &ExplainRequest) -> Explanation {
+    let mut explanation = Explanation::default();
+    let top = TopName::new();
+    // The views which were produced before an error are still returned
+    if let Err(err) = explain_views(vm, &top.name(), request, &mut explanation) {
+        explanation.phase = Some(crate::error_phase(&err));
+        explanation.diagnostics = crate::diagnostics(vm, &err);
+    }
+    explanation
+}
+
+fn explain_views(
+    vm: &Thread,
+    name: &str,
+    request: &ExplainRequest,
+    explanation: &mut Explanation,
+) -> Result<(), Error> {
+    let source = &request.source;
+    if request.wants(ExplainView::Expanded) {
+        let mut formatter = gluon_format::Formatter { expanded: true };
+        explanation.expanded = Some(vm.format_expr(&mut formatter, name, source)?);
+    }
+    if !request.wants(ExplainView::Typed) && !request.wants(ExplainView::Bytecode) {
+        return Ok(());
+    }
+
+    let (mut bindings, instructions) = {
+        let mut db = vm.get_database();
+        let mut compiler = vm.module_compiler(&mut db);
+        let expr = source
+            .expand_macro(&mut compiler, vm, name, source)
+            .map_err(|salvage| salvage.error)?;
+        let checked = expr
+            .typecheck(&mut compiler, vm, name, source)
+            .map_err(|salvage| salvage.error)?;
+
+        let mut bindings = Bindings(Vec::new());
+        bindings.visit_expr(checked.expr.expr());
+        let compiled = checked.compile(&mut compiler, vm, name, source, ())?;
+        (bindings.0, listing(&compiled.module.function))
+    };
+
+    if request.wants(ExplainView::Typed) {
+        let filemap = vm.get_database().get_filemap(name);
+        bindings.sort_by_key(|&(span, ..)| span.start());
+        let bindings = bindings
+            .into_iter()
+            .map(|(span, name, typ)| TypedBinding {
+                name: name.declared_name().into(),
+                typ: typ.to_string(),
+                span: filemap
+                    .as_ref()
+                    .and_then(|filemap| crate::source_span(filemap, span)),
+            })
+            .collect();
+        explanation.typed = Some(bindings);
+    }
+    if request.wants(ExplainView::Bytecode) {
+        explanation.bytecode = Some(instructions);
+    }
+    Ok(())
+}
+
+/// Collects every variable bound in an expression along with its type.
+struct Bindings(Vec<(Span<BytePos>, Symbol, ArcType)>);
+
+impl Bindings {
+    fn arguments(&mut self, args: &[Argument<SpannedIdent<Symbol>>]) {
+        self.0.extend(args.iter().map(|arg| {
+            (
+                arg.name.span,
+                arg.name.value.name.clone(),
+                arg.name.value.typ.clone(),
+            )
+        }));
+    }
+}
+
+impl<'a, 'ast> Visitor<'a, 'ast> for Bindings {
+    type Ident = Symbol;
+
+    fn visit_expr(&mut self, expr: &'a SpannedExpr<'ast, Symbol>) {
+        match &expr.value {
+            Expr::LetBindings(binds, _) => {
+                for bind in binds.iter() {
+                    self.arguments(&bind.args);
+                }
+            }
+            Expr::Lambda(lambda) => self.arguments(&lambda.args),
+            _ => (),
+        }
+        ast::walk_expr(self, expr);
+    }
+
+    fn visit_pattern(&mut self, pattern: &'a SpannedPattern<'ast, Symbol>) {
+        if let Pattern::Ident(id) = &pattern.value {
+            self.0.push((pattern.span, id.name.clone(), id.typ.clone()));
+        }
+        ast::walk_pattern(self, &pattern.value);
+    }
+}
+
+/// Lists the instructions of `function`, one per line with its index.
+fn listing(function: &CompiledFunction) -> Vec<String> {
+    function
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| format!("{:>4}  {:?}", index, instruction))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::Phase;
+
+    fn explain_source(source: &str, views: Vec<ExplainView>) -> Explanation {
+        explain(
+            crate::test_vm(),
+            &ExplainRequest {
+                source: source.into(),
+                views,
+            },
+        )
+    }
+
+    #[test]
+    fn explain_every_view() {
+        let explanation = explain_source("let add x y = x + y in add 1 2", Vec::new());
+        assert!(explanation.diagnostics.is_empty(), "{:?}", explanation);
+        assert!(explanation.expanded.is_some());
+        assert!(!explanation.bytecode.unwrap_or_default().is_empty());
+
+        let typed: Vec<_> = explanation
+            .typed
+            .unwrap_or_default()
+            .into_iter()
+            .map(|binding| (binding.name, binding.typ))
+            .collect();
+        assert_eq!(
+            typed,
+            [
+                ("add".to_string(), "Int -> Int -> Int".to_string()),
+                ("x".into(), "Int".into()),
+                ("y".into(), "Int".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn explain_selected_views() {
+        let explanation = explain_source("1 + 2", vec![ExplainView::Bytecode]);
+        assert!(explanation.bytecode.is_some(), "{:?}", explanation);
+        assert_eq!((explanation.expanded, explanation.typed), (None, None));
+
+        // The expanded source is still returned when typechecking fails
+        let explanation = explain_source("1 + \"\"", Vec::new());
+        assert_eq!(
+            explanation.phase,
+            Some(Phase::Typecheck),
+            "{:?}",
+            explanation
+        );
+        assert!(explanation.expanded.is_some());
+        assert_eq!(explanation.typed, None);
+    }
+}
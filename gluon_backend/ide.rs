@@ -0,0 +1,229 @@
+//! Editor support for the playground, backed by `gluon_completion`.
+//!
+//! Snippets being edited rarely typecheck, so like gluon's language server the information is
+//! taken from the part of the snippet the parser and typechecker could make sense of. The
+//! diagnostics explaining what they could not are returned along with it.
+
+use std::sync::Arc;
+
+use try_gluon_api::{
+    Completion, Completions, CursorRequest, Definition, Diagnostic, Hover, Signature, SignatureHelp,
+};
+
+use gluon::{
+    base::{
+        ast::SpannedExpr,
+        pos::{ByteOffset, BytePos, Span},
+        source::FileMap,
+        symbol::Symbol,
+    },
+    check::metadata,
+    compiler_pipeline::{MacroExpandable, Typecheckable},
+    Error, ThreadExt,
+};
+
+use crate::{EvalVm, TopName};
+
+struct Typechecked<'a> {
+    expr: &'a SpannedExpr<'a, Symbol>,
+    filemap: Arc<FileMap>,
+    /// The span of the whole snippet
+    span: Span<BytePos>,
+    /// The position of the cursor
+    pos: BytePos,
+}
+
+/// Typechecks the snippet and passes what could be typechecked of it to `f`, returning the result
+/// of `f` along with the errors in the snippet. Fails if nothing could be salvaged.
+fn typecheck<R>(
+    vm: &EvalVm,
+    request: &CursorRequest,
+    f: impl FnOnce(&Typechecked<'_>) -> R,
+) -> Result<(R, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let top = TopName::new();
+    let name = top.name();
+    let source = &request.source[..];
+
+    let mut errors = Vec::new();
+    let checked = {
+        let mut db = vm.get_database();
+        let mut compiler = vm.module_compiler(&mut db);
+        let expr = match source.expand_macro(&mut compiler, vm, &name, source) {
+            Ok(expr) => Some(expr),
+            Err(salvage) => {
+                errors.push(salvage.error);
+                salvage.value
+            }
+        };
+        expr.and_then(
+            |expr| match expr.typecheck(&mut compiler, vm, &name, source) {
+                Ok(checked) => Some(checked),
+                Err(salvage) => {
+                    errors.push(salvage.error);
+                    salvage.value
+                }
+            },
+        )
+    };
+    let diagnostics = errors
+        .iter()
+        .flat_map(|err: &Error| crate::diagnostics(vm, err))
+        .collect();
+    let checked = match checked {
+        Some(checked) => checked,
+        None => return Err(diagnostics),
+    };
+
+    let filemap = vm.get_database().get_filemap(&name).ok_or_else(|| {
+        vec![Diagnostic {
+            file: "<top>".into(),
+            span: None,
+            message: "Unable to find the source of the snippet".into(),
+        }]
+    })?;
+    let span = filemap.span();
+    let offset = request.offset.min(request.source.len());
+    let result = f(&Typechecked {
+        expr: checked.expr.expr(),
+        filemap,
+        span,
+        pos: span.start() + ByteOffset::from(offset as i64),
+    });
+    Ok((result, diagnostics))
+}
+
+pub fn complete(vm: &EvalVm, request: &CursorRequest) -> Completions {
+    let suggest = |checked: &Typechecked<'_>| {
+        gluon_completion::suggest(&vm.get_env(), checked.span, checked.expr, checked.pos)
+    };
+    match typecheck(vm, request, suggest) {
+        Ok((suggestions, diagnostics)) => Completions {
+            items: suggestions
+                .into_iter()
+                .map(|suggestion| Completion {
+                    name: suggestion.name,
+                    typ: suggestion
+                        .typ
+                        .either(|kind| kind.to_string(), |typ| typ.to_string()),
+                })
+                .collect(),
+            diagnostics,
+        },
+        Err(diagnostics) => Completions {
+            diagnostics,
+            ..Completions::default()
+        },
+    }
+}
+
+pub fn hover(vm: &EvalVm, request: &CursorRequest) -> Hover {
+    let find = |checked: &Typechecked<'_>| {
+        let env = vm.get_env();
+        let typ = gluon_completion::find(&env, checked.span, checked.expr, checked.pos)
+            .ok()
+            .map(|typ| typ.either(|kind| kind.to_string(), |typ| typ.to_string()));
+
+        let (_, metadata_map) = metadata::metadata(&env, checked.expr);
+        let doc =
+            gluon_completion::get_metadata(&metadata_map, checked.span, checked.expr, checked.pos)
+                .and_then(|metadata| metadata.comment.as_ref())
+                .map(|comment| comment.content.clone());
+        (typ, doc)
+    };
+    match typecheck(vm, request, find) {
+        Ok(((typ, doc), diagnostics)) => Hover {
+            typ,
+            doc,
+            diagnostics,
+        },
+        Err(diagnostics) => Hover {
+            diagnostics,
+            ..Hover::default()
+        },
+    }
+}
+
+pub fn signature_help(vm: &EvalVm, request: &CursorRequest) -> SignatureHelp {
+    let help = |checked: &Typechecked<'_>| {
+        gluon_completion::signature_help(&vm.get_env(), checked.span, checked.expr, checked.pos)
+            .map(|help| Signature {
+                name: help.name,
+                typ: help.typ.to_string(),
+                active_parameter: help.index,
+            })
+    };
+    match typecheck(vm, request, help) {
+        Ok((signature, diagnostics)) => SignatureHelp {
+            signature,
+            diagnostics,
+        },
+        Err(diagnostics) => SignatureHelp {
+            diagnostics,
+            ..SignatureHelp::default()
+        },
+    }
+}
+
+/// Finds where the binding at the cursor is defined.
+pub fn definition(vm: &EvalVm, request: &CursorRequest) -> Definition {
+    let find = |checked: &Typechecked<'_>| {
+        // A binding must be defined before it can be used so its first occurrence is the
+        // definition
+        gluon_completion::find_all_symbols(checked.span, checked.expr, checked.pos)
+            .ok()
+            .and_then(|(_, spans)| spans.into_iter().min_by_key(|span| span.start()))
+            .and_then(|span| crate::source_span(&checked.filemap, span))
+    };
+    match typecheck(vm, request, find) {
+        Ok((span, diagnostics)) => Definition { span, diagnostics },
+        Err(diagnostics) => Definition {
+            diagnostics,
+            ..Definition::default()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cursor_at_end(source: &str) -> CursorRequest {
+        CursorRequest {
+            source: source.into(),
+            offset: source.len(),
+        }
+    }
+
+    #[test]
+    fn complete_incomplete_field_access() {
+        let request = cursor_at_end("let record = { alpha = 1, beta = \"b\" }\nrecord.");
+        let completions = complete(crate::test_vm(), &request);
+        assert!(!completions.diagnostics.is_empty());
+        let names: Vec<_> = completions
+            .items
+            .iter()
+            .map(|item| &item.name[..])
+            .collect();
+        assert_eq!(names, ["alpha", "beta"], "{:?}", completions);
+    }
+
+    #[test]
+    fn hover_shows_the_type() {
+        let request = CursorRequest {
+            source: "let value = 1.5\nvalue".into(),
+            offset: "let value = 1.5\nva".len(),
+        };
+        let hover = hover(crate::test_vm(), &request);
+        assert_eq!(hover.typ.as_deref(), Some("Float"), "{:?}", hover);
+        assert!(hover.diagnostics.is_empty(), "{:?}", hover);
+    }
+
+    #[test]
+    fn signature_of_the_called_function() {
+        let request = cursor_at_end("let add x y : Int -> Int -> Int = x + y\nadd 1 ");
+        let help = signature_help(crate::test_vm(), &request);
+        let signature = help.signature.unwrap_or_else(|| panic!("{:?}", help));
+        assert_eq!(signature.name, "add");
+        assert_eq!(signature.typ, "Int -> Int -> Int");
+    }
+}
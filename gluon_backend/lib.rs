@@ -0,0 +1,584 @@
+pub use gluon_doc;
+
+mod capture;
+mod display;
+pub mod explain;
+pub mod ide;
+mod pool;
+mod prelude;
+mod project;
+mod random;
+mod sandbox;
+pub mod session;
+mod tree;
+
+use std::{
+    fmt,
+    ops::Deref,
+    result::Result as StdResult,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+pub use crate::{
+    prelude::{PreludeCache, PreludeLoad},
+    sandbox::SandboxPolicy,
+};
+
+use crate::{
+    pool::{SandboxThread, ThreadPool},
+    prelude::CachedPrelude,
+};
+
+use try_gluon_api::{
+    Cancellation, CheckResult, Diagnostic, EvalRequest, EvalResult, EvalStats, LimitExceeded,
+    LimitKind, Limits, OutputSink, Phase, Position, SourceSpan,
+};
+
+pub use gluon::{
+    base::{
+        error::InFile,
+        kind::{ArcKind, KindEnv},
+        pos::{BytePos, Span},
+        source::FileMap,
+        symbol::{Symbol, SymbolRef},
+        types::{Alias, ArcType, TypeEnv},
+    },
+    import::{add_extern_module, DefaultImporter, Import},
+    vm::{
+        self,
+        api::{Hole, OpaqueValue},
+        internal::ValuePrinter,
+        thread::ThreadInternal,
+    },
+    Result,
+};
+
+pub use gluon::*;
+
+use gluon::{
+    compiler_pipeline::{Compileable, Executable, MacroExpandable, Typecheckable},
+    vm::thread::RootedValue,
+};
+
+pub struct EmptyEnv;
+
+impl KindEnv for EmptyEnv {
+    fn find_kind(&self, _type_name: &SymbolRef) -> Option<ArcKind> {
+        None
+    }
+}
+
+impl TypeEnv for EmptyEnv {
+    type Type = ArcType;
+
+    fn find_type(&self, _id: &SymbolRef) -> Option<ArcType> {
+        None
+    }
+    fn find_type_info(&self, _id: &SymbolRef) -> Option<Alias<Symbol, ArcType>> {
+        None
+    }
+}
+
+/// The VM which snippets are evaluated on, along with the policy it was created with.
+#[derive(Clone, Debug)]
+pub struct EvalVm {
+    thread: RootedThread,
+    policy: SandboxPolicy,
+    /// The threads snippets are evaluated on, created on demand if `None`
+    pool: Option<Arc<ThreadPool>>,
+    /// The bytecode of the std prelude, which VMs created by `isolated` load instead of compiling
+    /// it. Collected from `thread` when first needed unless it was read from the cache.
+    prelude: Arc<OnceLock<Option<CachedPrelude>>>,
+}
+
+impl EvalVm {
+    /// Keeps `size` threads ready to evaluate on, refilling them in the background. `on_miss` is
+    /// called whenever an evaluation has to create its own thread as the pool was empty.
+    pub fn with_thread_pool(self, size: usize, on_miss: fn()) -> EvalVm {
+        if size == 0 {
+            return self;
+        }
+        EvalVm {
+            pool: Some(ThreadPool::spawn(&self.thread, size, on_miss)),
+            ..self
+        }
+    }
+
+    /// Creates a VM with the same policy for evaluations whose modules must not become visible to
+    /// other requests. The std prelude is loaded as bytecode, so this costs far less than
+    /// `make_eval_vm`.
+    pub(crate) fn isolated(&self) -> Result<EvalVm> {
+        let prelude = self.prelude.get_or_init(|| {
+            let start = Instant::now();
+            self.thread
+                .run_expr::<OpaqueValue<&Thread, Hole>>("", "()")
+                .ok()?;
+            prelude::collect(&self.thread, start.elapsed()).ok()
+        });
+
+        let mut vm = make_eval_vm(&self.policy)?;
+        if let Some(prelude) = prelude {
+            // A VM which failed to load part of the prelude compiles it instead
+            if prelude::load(&vm, prelude).is_err() {
+                vm = make_eval_vm(&self.policy)?;
+            }
+        }
+        Ok(EvalVm {
+            prelude: self.prelude.clone(),
+            ..vm
+        })
+    }
+
+    fn sandbox_thread(&self) -> vm::Result<SandboxThread> {
+        match &self.pool {
+            Some(pool) => pool.take(&self.thread),
+            None => SandboxThread::new(&self.thread),
+        }
+    }
+}
+
+impl Deref for EvalVm {
+    type Target = Thread;
+
+    fn deref(&self) -> &Self::Target {
+        &self.thread
+    }
+}
+
+pub fn make_eval_vm(policy: &SandboxPolicy) -> Result<EvalVm> {
+    let vm = RootedThread::new();
+
+    // Ensure the import macro cannot be abused to to open files
+    {
+        // Ensure the lock to `paths` are released
+        let import = Import::new(DefaultImporter);
+        import.paths.write().unwrap().clear();
+        vm.get_macros().insert(String::from("import"), import);
+    }
+
+    // Initialize the basic types such as `Bool` and `Option` so they are available when loading
+    // other modules
+    add_extern_module(&vm, "std.prim", crate::vm::primitives::load);
+
+    vm.run_expr::<OpaqueValue<&Thread, Hole>>(
+        "",
+        r#"//@NO-IMPLICIT-PRELUDE
+           let _ = import! std.types
+           let _ = import! std.prim
+           ()
+        "#,
+    )
+    .unwrap_or_else(|err| panic!("{}", err));
+
+    sandbox::add_prim_modules(&vm, policy)?;
+
+    // Run `IO` actions returned from the snippets so their output is visible
+    vm.run_io(true);
+
+    Ok(EvalVm {
+        thread: vm,
+        policy: policy.clone(),
+        pool: None,
+        prelude: Arc::default(),
+    })
+}
+
+/// Like `make_eval_vm` but loads the compiled std prelude from `cache`, compiling and writing it to
+/// `cache` if it has not been written for this version of gluon yet.
+pub fn make_eval_vm_with_prelude_cache(
+    policy: &SandboxPolicy,
+    cache: &PreludeCache,
+) -> Result<(EvalVm, PreludeLoad)> {
+    let (vm, load, prelude) = prelude::load_or_compile(cache, || make_eval_vm(policy))?;
+    let vm = EvalVm {
+        prelude: Arc::new(OnceLock::from(prelude)),
+        ..vm
+    };
+    Ok((vm, load))
+}
+
+/// The name a snippet is compiled under. The VM keeps the file map of the source last compiled
+/// under each name, so requests which run at the same time must use different names to find the
+/// spans of their own snippet. Names are reused once dropped so that the VM only holds on to a file
+/// map per concurrent request.
+pub(crate) struct TopName(usize);
+
+static FREE_TOP_NAMES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static TOP_NAMES: AtomicUsize = AtomicUsize::new(0);
+
+impl TopName {
+    pub(crate) fn new() -> TopName {
+        let free = FREE_TOP_NAMES.lock().unwrap().pop();
+        TopName(free.unwrap_or_else(|| TOP_NAMES.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    pub(crate) fn name(&self) -> String {
+        match self.0 {
+            0 => "<top>".into(),
+            index => format!("<top{}>", index),
+        }
+    }
+}
+
+impl Drop for TopName {
+    fn drop(&mut self) {
+        FREE_TOP_NAMES.lock().unwrap().push(self.0);
+    }
+}
+
+/// The file name `name` is reported as, every snippet is reported as `<top>`.
+fn file_name(name: &str) -> &str {
+    if name.starts_with("<top") {
+        "<top>"
+    } else {
+        name
+    }
+}
+
+const FUEL_LIMIT_MESSAGE: &str = "Thread has exceeded the allowed number of steps";
+const TIME_LIMIT_MESSAGE: &str = "Thread has exceeded the allowed exection time";
+const CANCELLED_MESSAGE: &str = "The evaluation was cancelled";
+
+/// Evaluates `request`, aborting as soon as possible once `cancellation` is cancelled.
+pub fn eval(
+    vm: &EvalVm,
+    request: &EvalRequest,
+    limits: &Limits,
+    cancellation: &Cancellation,
+) -> EvalResult {
+    eval_streaming(vm, request, limits, cancellation, Box::new(|_| ()))
+}
+
+/// Like `eval` but also passes the output to `on_output` while the snippet is running.
+pub fn eval_streaming(
+    vm: &EvalVm,
+    request: &EvalRequest,
+    limits: &Limits,
+    cancellation: &Cancellation,
+    on_output: OutputSink,
+) -> EvalResult {
+    if request.modules.is_empty() {
+        return eval_with_modules(vm, &[], request, limits, cancellation, on_output);
+    }
+
+    let modules = match project::load_order(&request.modules) {
+        Ok(modules) => modules,
+        Err(diagnostic) => return EvalResult::failure(Phase::Parse, vec![diagnostic]),
+    };
+
+    // Each project gets a VM of its own so that its modules never become visible to other
+    // requests
+    let mut result = match vm.isolated() {
+        Ok(project_vm) => eval_with_modules(
+            &project_vm,
+            &modules,
+            request,
+            limits,
+            cancellation,
+            on_output,
+        ),
+        Err(err) => return error_result(vm, err, limits),
+    };
+
+    // Report errors using the file names the modules were submitted as
+    for diagnostic in &mut result.diagnostics {
+        if let Some(module) = modules.iter().find(|module| module.name == diagnostic.file) {
+            diagnostic.file = module.file.into();
+        }
+    }
+    result
+}
+
+/// Loads `modules` and then evaluates `request.source` on a new thread of `global_vm`.
+fn eval_with_modules(
+    global_vm: &EvalVm,
+    modules: &[project::Module<'_>],
+    request: &EvalRequest,
+    limits: &Limits,
+    cancellation: &Cancellation,
+    on_output: OutputSink,
+) -> EvalResult {
+    let sources = modules
+        .iter()
+        .map(|module| (&*module.name, module.source))
+        .chain(Some(("<top>", &*request.source)));
+    for (file, source) in sources {
+        if let Err(diagnostic) = sandbox::check_imports(file, source) {
+            return EvalResult::failure(Phase::Parse, vec![diagnostic]);
+        }
+    }
+
+    let SandboxThread {
+        thread: vm,
+        fuel,
+        max_stack_depth,
+        max_memory,
+        ..
+    } = match global_vm.sandbox_thread() {
+        Ok(thread) => {
+            thread.configure(limits, cancellation);
+            thread
+        }
+        Err(err) => return error_result(global_vm, Error::VM(err), limits),
+    };
+
+    // Held until the diagnostics have been collected
+    let top = TopName::new();
+    let seed = request.seed.unwrap_or_else(random::new_seed);
+    let mut stats = EvalStats {
+        memory_limit: limits.memory,
+        ..EvalStats::default()
+    };
+    let ((result, used_random), output) = capture::capture(limits.output, on_output, || {
+        random::seeded(seed, || {
+            sandbox::with_files(&request.files, || {
+                run_phases(&vm, modules, &top.name(), &request.source, &mut stats)
+            })
+        })
+    });
+    // The hook does not run after the last step so the memory held at the end is included
+    stats.memory = max_memory
+        .load(Ordering::Relaxed)
+        .max(vm.allocated_memory());
+    stats.max_stack_depth = max_stack_depth.load(Ordering::Relaxed);
+
+    let result = match result {
+        Ok((value, typ)) => EvalResult {
+            tree: request
+                .tree
+                .as_ref()
+                .map(|options| tree::render(&vm.get_env(), &typ, value.get_variant(), options)),
+            ..EvalResult::success(
+                ValuePrinter::new(&EmptyEnv, &typ, value.get_variant(), &Default::default())
+                    .max_level(6)
+                    .to_string(),
+                typ.to_string(),
+            )
+        },
+        Err(err) => error_result(&vm, err, limits),
+    };
+
+    // Fuel is counted per step so every limit except the wall-clock time is reached the same way
+    // each time the snippet is evaluated. Random numbers are only the same if the seed was given.
+    let deterministic = result.phase != Some(Phase::Cancelled)
+        && result
+            .limit_exceeded
+            .is_none_or(|limit_exceeded| limit_exceeded.kind != LimitKind::Time)
+        && (request.seed.is_some() || !used_random);
+    EvalResult {
+        fuel_consumed: fuel.load(Ordering::Relaxed),
+        output: output.chunks,
+        output_truncated: output.truncated,
+        display: output.display,
+        deterministic,
+        // A snippet which failed before running never used the seed
+        seed: Some(seed).filter(|_| stats.execute_us.is_some()),
+        stats: Some(stats).filter(|_| request.stats),
+        ..result
+    }
+}
+
+/// Loads `modules` and evaluates `source` in the same steps as `run_expr`, timing each phase.
+fn run_phases<'vm>(
+    vm: &'vm Thread,
+    modules: &[project::Module<'_>],
+    name: &str,
+    source: &str,
+    stats: &mut EvalStats,
+) -> Result<(RootedValue<&'vm Thread>, ArcType)> {
+    // The modules are imported by the snippet so loading them is counted as part of parsing
+    timed(&mut stats.parse_us, || {
+        modules
+            .iter()
+            .try_for_each(|module| vm.load_script(&module.name, module.source))
+    })?;
+
+    let mut db = vm.get_database();
+    let mut compiler = vm.module_compiler(&mut db);
+    let expr = timed(&mut stats.parse_us, || {
+        source
+            .expand_macro(&mut compiler, vm, name, source)
+            .map_err(|salvage| salvage.error)
+    })?;
+    let checked = timed(&mut stats.typecheck_us, || {
+        expr.typecheck(&mut compiler, vm, name, source)
+            .map_err(|salvage| salvage.error)
+    })?;
+    let compiled = timed(&mut stats.compile_us, || {
+        checked.compile(&mut compiler, vm, name, source, ())
+    })?;
+    let executed = timed(&mut stats.execute_us, || {
+        futures::executor::block_on(compiled.run_expr(&mut compiler, vm, name, source, ()))
+    })?;
+    Ok((executed.value, executed.typ))
+}
+
+/// Runs `f`, adding how long it took in microseconds to `duration`.
+fn timed<T>(duration: &mut Option<u64>, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *duration = Some(duration.unwrap_or(0) + start.elapsed().as_micros() as u64);
+    result
+}
+
+/// Parses, expands macros in and typechecks `source` without running it.
+pub fn check(vm: &EvalVm, source: &str) -> CheckResult {
+    let top = TopName::new();
+    match vm.typecheck_str(&top.name(), source, None) {
+        Ok((_, typ)) => CheckResult {
+            typ: Some(typ.to_string()),
+            ..CheckResult::default()
+        },
+        Err(err) => CheckResult {
+            typ: None,
+            phase: Some(error_phase(&err)),
+            diagnostics: diagnostics(vm, &err),
+        },
+    }
+}
+
+fn error_result(thread: &Thread, err: Error, limits: &Limits) -> EvalResult {
+    let diagnostics = diagnostics(thread, &err);
+    if matches!(&err, Error::VM(vm::Error::Message(msg)) if msg == CANCELLED_MESSAGE) {
+        return EvalResult::failure(Phase::Cancelled, diagnostics);
+    }
+    match exceeded_limit(&err, limits) {
+        Some(limit_exceeded) => EvalResult {
+            limit_exceeded: Some(limit_exceeded),
+            ..EvalResult::failure(Phase::LimitExceeded, diagnostics)
+        },
+        None => EvalResult::failure(error_phase(&err), diagnostics),
+    }
+}
+
+pub(crate) fn error_phase(err: &Error) -> Phase {
+    match err {
+        Error::Parse(_) | Error::Macro(_) => Phase::Parse,
+        Error::Typecheck(_) => Phase::Typecheck,
+        Error::VM(_) => Phase::Runtime,
+        Error::Multiple(errors) => errors.iter().next().map_or(Phase::Compile, error_phase),
+        _ => Phase::Compile,
+    }
+}
+
+fn exceeded_limit(err: &Error, limits: &Limits) -> Option<LimitExceeded> {
+    let (kind, limit) = match err {
+        Error::VM(vm::Error::OutOfMemory { .. }) => (LimitKind::Memory, limits.memory as u64),
+        Error::VM(vm::Error::StackOverflow(_)) => (LimitKind::Stack, limits.stack as u64),
+        Error::VM(vm::Error::Message(msg)) if msg == FUEL_LIMIT_MESSAGE => {
+            (LimitKind::Fuel, limits.fuel)
+        }
+        Error::VM(vm::Error::Message(msg)) if msg == TIME_LIMIT_MESSAGE => {
+            (LimitKind::Time, limits.time_ms)
+        }
+        _ => return None,
+    };
+    Some(LimitExceeded { kind, limit })
+}
+
+pub(crate) fn diagnostics(thread: &Thread, err: &Error) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    collect_diagnostics(thread, err, &mut diagnostics);
+    diagnostics
+}
+
+fn collect_diagnostics(thread: &Thread, err: &Error, diagnostics: &mut Vec<Diagnostic>) {
+    match err {
+        Error::Parse(err) => in_file_diagnostics(thread, err, diagnostics),
+        Error::Typecheck(err) => in_file_diagnostics(thread, err, diagnostics),
+        Error::Macro(err) => in_file_diagnostics(thread, err, diagnostics),
+        Error::Multiple(errors) => {
+            for err in errors.iter() {
+                collect_diagnostics(thread, err, diagnostics);
+            }
+        }
+        _ => diagnostics.push(Diagnostic {
+            file: "<top>".into(),
+            span: None,
+            message: err.to_string(),
+        }),
+    }
+}
+
+fn in_file_diagnostics<E>(thread: &Thread, err: &InFile<E>, diagnostics: &mut Vec<Diagnostic>)
+where
+    E: fmt::Display,
+{
+    let file = err.source_name();
+    let filemap = thread.get_database().get_filemap(file);
+    diagnostics.extend(err.errors().iter().map(|err| {
+        Diagnostic {
+            file: file_name(file).into(),
+            span: filemap
+                .as_ref()
+                .and_then(|filemap| source_span(filemap, err.span)),
+            message: err.value.to_string(),
+        }
+    }));
+}
+
+pub(crate) fn source_span(filemap: &FileMap, span: Span<BytePos>) -> Option<SourceSpan> {
+    Some(SourceSpan {
+        start: position(filemap, span.start())?,
+        end: position(filemap, span.end())?,
+    })
+}
+
+fn position(filemap: &FileMap, pos: BytePos) -> Option<Position> {
+    let location = filemap.location(pos)?;
+    Some(Position {
+        line: location.line.to_usize() + 1,
+        column: location.column.to_usize() + 1,
+    })
+}
+
+pub fn format_expr(thread: &Thread, input: &str) -> StdResult<String, String> {
+    thread
+        .format_expr(&mut gluon_format::Formatter::default(), "try", input)
+        .map_err(|err| err.to_string())
+}
+
+pub fn generate_doc(options: &gluon_doc::Options) -> StdResult<(), anyhow::Error> {
+    gluon_doc::generate(options, &gluon::new_vm())
+}
+
+/// The VM shared by the tests, as loading the std library again for every test is slow.
+#[cfg(test)]
+pub(crate) fn test_vm() -> &'static EvalVm {
+    static VM: OnceLock<EvalVm> = OnceLock::new();
+    VM.get_or_init(|| make_eval_vm(&SandboxPolicy::default()).unwrap())
+}
+
+/// Evaluates `source` on `test_vm`, `configure` fills in the rest of the request.
+#[cfg(test)]
+pub(crate) fn test_eval_source(
+    source: &str,
+    limits: &Limits,
+    configure: impl FnOnce(&mut EvalRequest),
+) -> EvalResult {
+    let mut request = EvalRequest {
+        source: source.into(),
+        ..EvalRequest::default()
+    };
+    configure(&mut request);
+    eval(test_vm(), &request, limits, &Cancellation::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_names_are_unique_while_held() {
+        let names: Vec<_> = (0..3).map(|_| TopName::new()).collect();
+        let mut unique: Vec<_> = names.iter().map(TopName::name).collect();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), names.len());
+        assert!(unique.iter().all(|name| file_name(name) == "<top>"));
+    }
+}
@@ -0,0 +1,245 @@
+//! Threads which snippets are evaluated on, created ahead of time so that creating and configuring
+//! a thread is not on the path of a request. Every thread is only used for a single evaluation and
+//! the pool is refilled in the background as threads are taken.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use try_gluon_api::{Cancellation, Limits};
+
+use gluon::{
+    vm::{
+        self,
+        api::{Hole, OpaqueValue},
+        thread::HookFlags,
+    },
+    RootedThread, Thread, ThreadExt,
+};
+
+use crate::{CANCELLED_MESSAGE, FUEL_LIMIT_MESSAGE, TIME_LIMIT_MESSAGE};
+
+/// The limits of the evaluation running on a thread, checked by the thread's hook.
+#[derive(Debug)]
+struct Budget {
+    fuel: u64,
+    start: Instant,
+    time_limit: Duration,
+    cancellation: Cancellation,
+}
+
+impl Budget {
+    fn check(&self, consumed: u64) -> vm::Result<()> {
+        if self.cancellation.is_cancelled() {
+            Err(vm::Error::Message(CANCELLED_MESSAGE.into()))
+        } else if consumed > self.fuel {
+            Err(vm::Error::Message(FUEL_LIMIT_MESSAGE.into()))
+        } else if self.start.elapsed() >= self.time_limit {
+            Err(vm::Error::Message(TIME_LIMIT_MESSAGE.into()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// A thread with the hook which enforces the fuel, time and cancellation of an evaluation already
+/// installed. The hook does nothing until the limits are set by `configure`.
+#[derive(Debug)]
+pub(crate) struct SandboxThread {
+    pub thread: RootedThread,
+    /// The number of steps taken, see `Limits::fuel`
+    pub fuel: Arc<AtomicU64>,
+    /// The largest number of stack frames seen by the hook
+    pub max_stack_depth: Arc<AtomicUsize>,
+    /// The largest number of bytes the thread held when the hook ran
+    pub max_memory: Arc<AtomicUsize>,
+    budget: Arc<OnceLock<Budget>>,
+}
+
+impl SandboxThread {
+    pub fn new(global_vm: &Thread) -> vm::Result<SandboxThread> {
+        let thread = global_vm.new_thread()?;
+        let fuel = Arc::new(AtomicU64::new(0));
+        let max_stack_depth = Arc::new(AtomicUsize::new(0));
+        let max_memory = Arc::new(AtomicUsize::new(0));
+        let budget = Arc::new(OnceLock::<Budget>::new());
+        {
+            // Prevent infinite loops from running forever. Every call and line executed consumes
+            // one unit of fuel which makes the limit independent of the load on the server, the
+            // wall-clock limit is only a backstop in case a single step takes a long time.
+            let (fuel, max_stack_depth, max_memory, budget) = (
+                fuel.clone(),
+                max_stack_depth.clone(),
+                max_memory.clone(),
+                budget.clone(),
+            );
+            let mut context = thread.context();
+            context.set_hook(Some(Box::new(move |thread, info| {
+                // Every call runs the hook so no frame is missed
+                max_stack_depth.fetch_max(info.stack_info_len(), Ordering::Relaxed);
+                max_memory.fetch_max(thread.allocated_memory(), Ordering::Relaxed);
+                let consumed = fuel.fetch_add(1, Ordering::Relaxed) + 1;
+                Poll::Ready(budget.get().map_or(Ok(()), |budget| budget.check(consumed)))
+            })));
+            context.set_hook_mask(HookFlags::LINE_FLAG | HookFlags::CALL_FLAG);
+        }
+        Ok(SandboxThread {
+            thread,
+            fuel,
+            max_stack_depth,
+            max_memory,
+            budget,
+        })
+    }
+
+    /// Applies `limits` to the thread, the time limit counts from this call.
+    pub fn configure(&self, limits: &Limits, cancellation: &Cancellation) {
+        // Prevent a single thread from allocating to much memory
+        self.thread.set_memory_limit(limits.memory);
+        // Prevent the stack from consuming to much memory
+        self.thread.context().set_max_stack_size(limits.stack);
+
+        let budget = Budget {
+            fuel: limits.fuel,
+            start: Instant::now(),
+            time_limit: Duration::from_millis(limits.time_ms),
+            cancellation: cancellation.clone(),
+        };
+        self.budget
+            .set(budget)
+            .expect("A sandbox thread is only used for a single evaluation");
+    }
+}
+
+/// Keeps `size` threads of a VM ready.
+#[derive(Debug)]
+pub(crate) struct ThreadPool {
+    threads: Mutex<Vec<SandboxThread>>,
+    size: usize,
+    /// Wakes the thread which refills the pool
+    refill: SyncSender<()>,
+    /// Called when a thread had to be created on the path of a request
+    on_miss: fn(),
+}
+
+impl ThreadPool {
+    /// Creates the pool along with the thread which fills it. The pool is first filled after
+    /// the std prelude has been compiled, so that the first evaluation does not compile it.
+    pub fn spawn(global_vm: &RootedThread, size: usize, on_miss: fn()) -> Arc<ThreadPool> {
+        let (refill, wake) = mpsc::sync_channel(1);
+        let pool = Arc::new(ThreadPool {
+            threads: Mutex::new(Vec::with_capacity(size)),
+            size,
+            refill,
+            on_miss,
+        });
+
+        let global_vm = global_vm.clone();
+        let weak = Arc::downgrade(&pool);
+        std::thread::Builder::new()
+            .name("sandbox-pool".into())
+            .spawn(move || {
+                let _ = global_vm.run_expr::<OpaqueValue<&Thread, Hole>>("<warm up>", "()");
+                fill(&global_vm, &weak, &wake);
+            })
+            .expect("Unable to spawn the thread which fills the sandbox pool");
+        pool
+    }
+
+    pub fn take(&self, global_vm: &Thread) -> vm::Result<SandboxThread> {
+        let thread = self.threads.lock().unwrap().pop();
+        // The pool is already being refilled if the channel is full
+        let _ = self.refill.try_send(());
+        match thread {
+            Some(thread) => Ok(thread),
+            None => {
+                (self.on_miss)();
+                SandboxThread::new(global_vm)
+            }
+        }
+    }
+}
+
+/// Refills `pool` each time it is woken, until the pool is dropped.
+fn fill(global_vm: &Thread, pool: &Weak<ThreadPool>, wake: &Receiver<()>) {
+    loop {
+        let pool = match pool.upgrade() {
+            Some(pool) => pool,
+            None => return,
+        };
+        while pool.threads.lock().unwrap().len() < pool.size {
+            match SandboxThread::new(global_vm) {
+                Ok(thread) => pool.threads.lock().unwrap().push(thread),
+                // Evaluations create their own threads until the pool can be refilled
+                Err(_) => break,
+            }
+        }
+        drop(pool);
+
+        if wake.recv().is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::{EvalRequest, LimitKind};
+
+    use crate::SandboxPolicy;
+
+    #[test]
+    fn pooled_threads_enforce_limits() {
+        let vm = crate::make_eval_vm(&SandboxPolicy::default())
+            .unwrap()
+            .with_thread_pool(1, || ());
+        let cancellation = Cancellation::default();
+
+        let request = EvalRequest::parse("1 + 2").unwrap();
+        for _ in 0..3 {
+            let result = crate::eval(&vm, &request, &Limits::default(), &cancellation);
+            assert_eq!(result.value.as_deref(), Some("3"), "{:?}", result);
+        }
+
+        let request = EvalRequest::parse("let f x = f x in f 0").unwrap();
+        let limits = Limits {
+            fuel: 100,
+            ..Limits::default()
+        };
+        let result = crate::eval(&vm, &request, &limits, &cancellation);
+        assert_eq!(
+            result.limit_exceeded.map(|limit| limit.kind),
+            Some(LimitKind::Fuel),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn stats_are_collected() {
+        let source = "let f n = if n == 0 then 0 else 1 + f (n - 1) in f 20";
+        let limits = Limits::default();
+
+        let result = crate::test_eval_source(source, &limits, |request| request.stats = true);
+        let stats = result.stats.unwrap_or_else(|| panic!("{:?}", result));
+        assert!(stats.parse_us.is_some() && stats.execute_us.is_some());
+        assert!(stats.max_stack_depth >= 20, "{:?}", stats);
+        assert!(
+            stats.memory > 0 && stats.memory <= limits.memory,
+            "{:?}",
+            stats
+        );
+        assert_eq!(stats.memory_limit, limits.memory);
+
+        let result = crate::test_eval_source(source, &limits, |_| ());
+        assert_eq!(result.stats, None);
+    }
+}
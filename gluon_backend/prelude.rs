@@ -0,0 +1,297 @@
+//! Persists the compiled std prelude so that a new process loads its bytecode instead of compiling
+//! the std modules again, which is most of the time it takes to start.
+//!
+//! The cache is a JSON file with the bytecode of every module the prelude depends on, in the order
+//! they must be loaded. Bytecode is only valid for the version of gluon which compiled it and the
+//! sandboxed modules it was compiled with, so each version of a backend has a file of its own.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashSet},
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io::{self, BufReader, BufWriter},
+    ops::Deref,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use gluon::{
+    vm::api::{Hole, OpaqueValue},
+    Thread, ThreadExt,
+};
+
+/// The modules which every evaluation depends on.
+const ROOTS: &[&str] = &["std.types", "std.prelude"];
+
+/// Where the compiled prelude of a backend is persisted.
+#[derive(Clone, Debug)]
+pub struct PreludeCache {
+    pub dir: PathBuf,
+    /// The version of gluon the backend is built with
+    pub version: String,
+}
+
+/// How the prelude of a VM was loaded.
+#[derive(Debug)]
+pub enum PreludeLoad {
+    Cached {
+        elapsed: Duration,
+        /// How long compiling the prelude took when the cache was written
+        compiled: Duration,
+    },
+    Compiled {
+        elapsed: Duration,
+        /// Why the cache could not be read or written, if it was not just missing
+        cache_error: Option<String>,
+    },
+}
+
+impl fmt::Display for PreludeLoad {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PreludeLoad::Cached { elapsed, compiled } => write!(
+                f,
+                "Loaded the prelude from the cache in {:?} (compiling it took {:?})",
+                elapsed, compiled
+            ),
+            PreludeLoad::Compiled {
+                elapsed,
+                cache_error,
+            } => {
+                write!(f, "Compiled the prelude in {:?}", elapsed)?;
+                match cache_error {
+                    Some(err) => write!(f, " as the cache could not be used: {}", err),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CachedPrelude {
+    /// How long compiling the prelude took when the cache was written
+    compile_ms: u64,
+    modules: Vec<CachedModule>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum CachedModule {
+    /// A module implemented in Rust, which only needs to be imported
+    Extern(String),
+    Bytecode {
+        name: String,
+        bytecode: serde_json::Value,
+    },
+}
+
+impl PreludeCache {
+    fn path(&self) -> PathBuf {
+        // Both backends are built from this source so the crate name tells them apart. The
+        // sandboxed modules can change without a new version of gluon so they are hashed as well
+        self.dir.join(format!(
+            "{}-{}-{:016x}.json",
+            env!("CARGO_PKG_NAME"),
+            self.version,
+            sources_hash(crate::sandbox::SANDBOXED_PRIMS)
+        ))
+    }
+
+    /// Reads the cache, `None` if it has not been written for this version yet.
+    fn read(&self) -> anyhow::Result<Option<CachedPrelude>> {
+        match fs::File::open(self.path()) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes `prelude` to the cache.
+    fn write(&self, prelude: &CachedPrelude) -> anyhow::Result<()> {
+        // Written to a temporary file first so that a process which starts meanwhile never reads
+        // a partially written cache
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path();
+        let temporary = path.with_extension("tmp");
+        serde_json::to_writer(BufWriter::new(fs::File::create(&temporary)?), prelude)?;
+        fs::rename(&temporary, &path)?;
+        Ok(())
+    }
+}
+
+/// Hashes the modules which are compiled from `sources` in place of the std modules of the same
+/// name.
+fn sources_hash(sources: &[(&str, &str)]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    sources.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Collects the bytecode of the prelude, which `vm` has compiled in `compile_time`.
+pub(crate) fn collect(vm: &Thread, compile_time: Duration) -> anyhow::Result<CachedPrelude> {
+    let mut modules = Vec::new();
+    let mut visited = HashSet::new();
+    for root in ROOTS {
+        collect_modules(vm, root, &mut visited, &mut modules)?;
+    }
+    Ok(CachedPrelude {
+        compile_ms: compile_time.as_millis() as u64,
+        modules,
+    })
+}
+
+/// Loads the modules of `prelude` into `vm`.
+pub(crate) fn load(vm: &Thread, prelude: &CachedPrelude) -> anyhow::Result<()> {
+    for module in &prelude.modules {
+        match module {
+            CachedModule::Extern(name) => {
+                let source = format!("//@NO-IMPLICIT-PRELUDE\nimport! {}", name);
+                vm.run_expr::<OpaqueValue<&Thread, Hole>>("", &source)?;
+            }
+            CachedModule::Bytecode { name, bytecode } => {
+                // Skips the modules which `make_eval_vm` compiled from source already
+                if vm.get_database().get_filemap(name).is_none() {
+                    vm.load_bytecode(name, bytecode.clone())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds `name` and the modules it imports to `modules`, each module after its imports.
+fn collect_modules(
+    vm: &Thread,
+    name: &str,
+    visited: &mut HashSet<String>,
+    modules: &mut Vec<CachedModule>,
+) -> anyhow::Result<()> {
+    if !visited.insert(name.to_string()) {
+        return Ok(());
+    }
+    // Only modules loaded from source have a file map
+    let filemap = match vm.get_database().get_filemap(name) {
+        Some(filemap) => filemap,
+        None => {
+            modules.push(CachedModule::Extern(name.into()));
+            return Ok(());
+        }
+    };
+    for import in imports(filemap.src()) {
+        collect_modules(vm, import, visited, modules)?;
+    }
+
+    let bytecode = vm
+        .compile_to_bytecode(name, filemap.src(), serde_json::value::Serializer)
+        .map_err(|err| anyhow::anyhow!("Unable to compile `{}`: {:?}", name, err))?;
+    modules.push(CachedModule::Bytecode {
+        name: name.into(),
+        bytecode,
+    });
+    Ok(())
+}
+
+/// The modules imported by `source`.
+fn imports(source: &str) -> impl Iterator<Item = &str> {
+    source
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("//"))
+        .flat_map(|line| line.split("import!").skip(1))
+        .filter_map(|rest| {
+            let rest = rest.trim_start();
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            Some(&rest[..end]).filter(|name| !name.is_empty())
+        })
+}
+
+/// Creates a VM with `make_vm` and loads the prelude into it from `cache`. If the cache can not be
+/// used the prelude is compiled instead and written to the cache. The prelude is returned as well
+/// unless it could neither be read nor collected.
+pub(crate) fn load_or_compile<V>(
+    cache: &PreludeCache,
+    make_vm: impl Fn() -> crate::Result<V>,
+) -> crate::Result<(V, PreludeLoad, Option<CachedPrelude>)>
+where
+    V: Deref<Target = Thread>,
+{
+    let start = Instant::now();
+    let cache_error = match cache.read() {
+        Ok(Some(prelude)) => {
+            let vm = make_vm()?;
+            match load(&vm, &prelude) {
+                Ok(()) => {
+                    let elapsed = start.elapsed();
+                    let compiled = Duration::from_millis(prelude.compile_ms);
+                    return Ok((vm, PreludeLoad::Cached { elapsed, compiled }, Some(prelude)));
+                }
+                // Part of the prelude may be loaded so it is compiled on a new VM
+                Err(err) => Some(err.to_string()),
+            }
+        }
+        Ok(None) => None,
+        Err(err) => Some(err.to_string()),
+    };
+
+    let start = Instant::now();
+    let vm = make_vm()?;
+    vm.run_expr::<OpaqueValue<&Thread, Hole>>("", "()")?;
+    let elapsed = start.elapsed();
+
+    let (prelude, write_error) = match collect(&vm, elapsed) {
+        Ok(prelude) => {
+            let write_error = cache.write(&prelude).err().map(|err| err.to_string());
+            (Some(prelude), write_error)
+        }
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let cache_error = cache_error.or(write_error);
+    Ok((
+        vm,
+        PreludeLoad::Compiled {
+            elapsed,
+            cache_error,
+        },
+        prelude,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_imports() {
+        let source = r#"
+            //@NO-IMPLICIT-PRELUDE
+            // import! std.commented
+            let { Functor } = import! std.functor
+            let int = import!  std.int.prim in
+            { map = (import! std.list).map }
+        "#;
+        assert_eq!(
+            imports(source).collect::<Vec<_>>(),
+            ["std.functor", "std.int.prim", "std.list"]
+        );
+    }
+
+    #[test]
+    fn cache_depends_on_the_sandboxed_sources() {
+        let sources = [("std.io.prim", "let x = 1\n{ x }")];
+        let changed = [("std.io.prim", "let x = 2\n{ x }")];
+        assert_ne!(sources_hash(&sources), sources_hash(&changed));
+
+        let cache = PreludeCache {
+            dir: PathBuf::from("cache"),
+            version: "0.18.0".into(),
+        };
+        let name = format!(
+            "{:016x}.json",
+            sources_hash(crate::sandbox::SANDBOXED_PRIMS)
+        );
+        assert!(cache.path().to_string_lossy().ends_with(&name));
+    }
+}
@@ -0,0 +1,163 @@
+//! Support for evaluating snippets which consist of several modules. The modules are loaded in
+//! dependency order before the main source is run so that `import!` can find them.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use try_gluon_api::Diagnostic;
+
+pub(crate) struct Module<'a> {
+    /// The name used to import the module (`data.list` for `data/list.glu`)
+    pub name: String,
+    /// The file name the module was submitted as
+    pub file: &'a str,
+    pub source: &'a str,
+}
+
+/// Returns the modules ordered so that every module comes after the modules it imports.
+pub(crate) fn load_order(
+    modules: &BTreeMap<String, String>,
+) -> Result<Vec<Module<'_>>, Diagnostic> {
+    let modules = modules
+        .iter()
+        .map(|(file, source)| {
+            Ok(Module {
+                name: module_name(file)?,
+                file,
+                source,
+            })
+        })
+        .collect::<Result<Vec<_>, Diagnostic>>()?;
+
+    let mut order = Vec::with_capacity(modules.len());
+    let mut visited = BTreeSet::new();
+    for i in 0..modules.len() {
+        visit(&modules, i, &mut Vec::new(), &mut visited, &mut order)?;
+    }
+
+    let mut modules: Vec<_> = modules.into_iter().map(Some).collect();
+    Ok(order
+        .into_iter()
+        .map(|i| modules[i].take().unwrap())
+        .collect())
+}
+
+fn visit(
+    modules: &[Module<'_>],
+    i: usize,
+    stack: &mut Vec<usize>,
+    visited: &mut BTreeSet<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), Diagnostic> {
+    if visited.contains(&i) {
+        return Ok(());
+    }
+    if stack.contains(&i) {
+        return Err(Diagnostic {
+            file: modules[i].file.into(),
+            span: None,
+            message: format!("Module `{}` is part of an import cycle", modules[i].name),
+        });
+    }
+
+    stack.push(i);
+    for import in imports(modules[i].source) {
+        if let Some(dependency) = modules.iter().position(|module| module.name == import) {
+            visit(modules, dependency, stack, visited, order)?;
+        }
+    }
+    stack.pop();
+
+    visited.insert(i);
+    order.push(i);
+    Ok(())
+}
+
+/// Finds the names of all modules imported by `source`.
+fn imports(source: &str) -> impl Iterator<Item = &str> {
+    source.match_indices("import!").map(move |(i, _)| {
+        let rest = source[i + "import!".len()..].trim_start();
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(rest.len());
+        &rest[..end]
+    })
+}
+
+fn module_name(file: &str) -> Result<String, Diagnostic> {
+    let error = |message: String| Diagnostic {
+        file: file.into(),
+        span: None,
+        message,
+    };
+
+    let path = file.trim_end_matches(".glu");
+    let is_identifier = |segment: &str| {
+        let mut chars = segment.chars();
+        chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || c == '_')
+    };
+    if !path.split('/').all(is_identifier) {
+        return Err(error(format!("`{}` is not a valid module file name", file)));
+    }
+
+    let name = path.replace('/', ".");
+    if name == "std" || name.starts_with("std.") || name == "try" || name.starts_with("try.") {
+        return Err(error(format!("The module name `{}` is reserved", name)));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modules(modules: &[(&str, &str)]) -> BTreeMap<String, String> {
+        modules
+            .iter()
+            .map(|&(file, source)| (file.into(), source.into()))
+            .collect()
+    }
+
+    #[test]
+    fn dependencies_are_loaded_first() {
+        let modules = modules(&[
+            ("a.glu", "let b = import! b\nlet c = import! data.c\nb"),
+            ("b.glu", "import! data.c"),
+            ("data/c.glu", "1"),
+        ]);
+        let order = load_order(&modules).unwrap();
+        assert_eq!(
+            order.iter().map(|m| &m.name[..]).collect::<Vec<_>>(),
+            ["data.c", "b", "a"]
+        );
+    }
+
+    #[test]
+    fn cyclic_imports_are_rejected() {
+        let modules = modules(&[("a.glu", "import! b"), ("b.glu", "import! a")]);
+        assert!(load_order(&modules).is_err());
+    }
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        let modules = modules(&[("std/io.glu", "1")]);
+        assert!(load_order(&modules).is_err());
+    }
+
+    #[test]
+    fn projects_are_isolated() {
+        use try_gluon_api::{Limits, Phase};
+
+        let project = modules(&[("data/c.glu", "let x = 1\n{ x }")]);
+        for _ in 0..2 {
+            let result =
+                crate::test_eval_source("(import! data.c).x + 1", &Limits::default(), |request| {
+                    request.modules = project.clone()
+                });
+            assert_eq!(result.value.as_deref(), Some("2"), "{:?}", result);
+        }
+        // The modules of a project are not visible to later requests
+        let result = crate::test_eval_source("(import! data.c).x", &Limits::default(), |_| ());
+        assert_eq!(result.phase, Some(Phase::Parse), "{:?}", result);
+    }
+}
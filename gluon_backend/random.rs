@@ -0,0 +1,164 @@
+//! Backs the random number generation of `std.random` by a generator seeded by each evaluation so
+//! that an evaluation can be reproduced by evaluating it again with the same seed.
+
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use gluon::{
+    vm::{self, api::IO, primitive, record, ExternModule},
+    Thread,
+};
+
+thread_local! {
+    static RNG: Cell<Rng> = Cell::new(Rng::new(0));
+    /// Whether the running evaluation has generated a random number
+    static USED: Cell<bool> = Cell::new(false);
+}
+
+/// A SplitMix64 generator, which is small and gives the same numbers on every platform.
+#[derive(Clone, Copy, Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u32) -> Rng {
+        Rng(u64::from(seed))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `low..high`, `high` must be greater than `low`.
+    fn range(&mut self, low: i64, high: i64) -> i64 {
+        let span = (i128::from(high) - i128::from(low)) as u128;
+        (i128::from(low) + (u128::from(self.next_u64()) % span) as i128) as i64
+    }
+}
+
+/// A seed for an evaluation which did not ask for a specific one.
+pub(crate) fn new_seed() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// Runs `f` with the random number generator seeded by `seed`. Returns whether `f` generated any
+/// random numbers, if it did not its result does not depend on the seed.
+pub(crate) fn seeded<R>(seed: u32, f: impl FnOnce() -> R) -> (R, bool) {
+    RNG.with(|rng| rng.set(Rng::new(seed)));
+    USED.with(|used| used.set(false));
+    let result = f();
+    (result, USED.with(|used| used.replace(false)))
+}
+
+fn with_rng<T>(f: impl FnOnce(&mut Rng) -> T) -> T {
+    USED.with(|used| used.set(true));
+    RNG.with(|cell| {
+        let mut rng = cell.get();
+        let value = f(&mut rng);
+        cell.set(rng);
+        value
+    })
+}
+
+fn next_int(_: ()) -> IO<i64> {
+    IO::Value(with_rng(|rng| rng.next_u64() as i64))
+}
+
+fn next_float(_: ()) -> IO<f64> {
+    // The 53 high bits give every float in `0.0..1.0` which is a multiple of 2^-53
+    IO::Value(with_rng(|rng| {
+        (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }))
+}
+
+fn gen_int_range(low: i64, high: i64) -> IO<i64> {
+    if low >= high {
+        return IO::Exception(format!("Empty range: {}..{}", low, high));
+    }
+    IO::Value(with_rng(|rng| rng.range(low, high)))
+}
+
+pub(crate) fn load(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            next_int => primitive!(1, "try.random.next_int", next_int),
+            next_float => primitive!(1, "try.random.next_float", next_float),
+            gen_int_range => primitive!(2, "try.random.gen_int_range", gen_int_range)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::{Limits, Phase};
+
+    #[test]
+    fn ranges_are_respected() {
+        let mut rng = Rng::new(1);
+        for &(low, high) in &[(0, 1), (-5, 5), (i64::MIN, i64::MAX), (10, 13)] {
+            for _ in 0..100 {
+                let value = rng.range(low, high);
+                assert!(
+                    low <= value && value < high,
+                    "{} in {}..{}",
+                    value,
+                    low,
+                    high
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn seeds_reproduce_evaluations() {
+        let source = r#"
+            let random = import! std.random
+            random.gen_int_range 0 1000000
+        "#;
+        let limits = Limits::default();
+
+        let first = crate::test_eval_source(source, &limits, |request| request.seed = Some(7));
+        assert!(first.value.is_some(), "{:?}", first);
+        assert_eq!(first.seed, Some(7));
+        assert!(first.deterministic);
+        let again = crate::test_eval_source(source, &limits, |request| request.seed = Some(7));
+        assert_eq!(again.value, first.value);
+
+        let unseeded = crate::test_eval_source(source, &limits, |_| ());
+        assert!(unseeded.seed.is_some());
+        assert!(!unseeded.deterministic);
+        let replayed =
+            crate::test_eval_source(source, &limits, |request| request.seed = unseeded.seed);
+        assert_eq!(replayed.value, unseeded.value);
+
+        // The seed is only reported if the snippet ran
+        let failed = crate::test_eval_source("undefined_binding", &limits, |request| {
+            request.seed = Some(7)
+        });
+        assert_eq!(failed.phase, Some(Phase::Typecheck), "{:?}", failed);
+        assert_eq!(failed.seed, None);
+    }
+
+    #[test]
+    fn host_generator_is_not_importable() {
+        let result = crate::test_eval_source(
+            r#"
+            let prim = import! std.random.host_prim
+            prim.next_int ()
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_eq!(result.phase, Some(Phase::Parse), "{:?}", result);
+        assert!(!result.deterministic, "{:?}", result);
+    }
+}
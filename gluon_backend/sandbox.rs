@@ -0,0 +1,421 @@
+//! Decides which host capabilities the evaluated snippets have access to. Primitive modules which
+//! can reach the host are either refused, replaced by a stub or backed by a virtual filesystem
+//! which is seeded by each request.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use gluon::{
+    import::add_extern_module,
+    vm::{
+        self,
+        api::{generic::A, OpaqueValue, IO},
+        primitive, record, ExternModule,
+    },
+    RootedThread, Thread, ThreadExt,
+};
+
+use try_gluon_api::Diagnostic;
+
+use crate::{capture, display, random};
+
+type Loader = fn(&Thread) -> vm::Result<ExternModule>;
+
+/// Primitive modules which only operate on values.
+const PURE_PRIMS: &[(&str, Loader)] = &[
+    ("std.byte.prim", crate::vm::primitives::load_byte),
+    ("std.int.prim", crate::vm::primitives::load_int),
+    ("std.float.prim", crate::vm::primitives::load_float),
+    ("std.string.prim", crate::vm::primitives::load_string),
+    ("std.char.prim", crate::vm::primitives::load_char),
+    ("std.array.prim", crate::vm::primitives::load_array),
+    ("std.lazy.prim", crate::vm::lazy::load),
+    ("std.reference.prim", crate::vm::reference::load),
+    ("std.json.prim", crate::vm::api::json::load),
+];
+
+/// Primitive modules which access the filesystem or other processes on the host.
+const HOST_PRIMS: &[(&str, Loader)] = &[
+    ("std.fs.prim", crate::vm::primitives::load_fs),
+    ("std.path.prim", crate::vm::primitives::load_path),
+    ("std.process.prim", crate::std_lib::process::load),
+];
+
+/// Replaces `std.io.prim` in the sandbox. Printing is captured (see `capture`), files are read
+/// from the virtual filesystem and reading stdin or loading and running other code is refused.
+/// Only the fields which can not reach the host are taken from the real implementation, which is
+/// registered as `std.io.host_prim` and can not be imported by snippets (see `check_imports`).
+/// `read_file`, `write_slice_file` and `flush_file` need a `File`, which the sandbox never opens.
+const IO_PRIM: &str = r#"//@NO-IMPLICIT-PRELUDE
+let prim @ { File, OpenOptions } = import! std.io.host_prim
+let capture = import! try.io
+let fs = import! try.fs
+let { refuse } = import! try.sandbox
+{
+    File,
+    OpenOptions,
+    flat_map = prim.flat_map,
+    wrap = prim.wrap,
+    catch = prim.catch,
+    read_file = prim.read_file,
+    write_slice_file = prim.write_slice_file,
+    flush_file = prim.flush_file,
+    print = capture.print,
+    println = capture.println,
+    eprint = capture.eprint,
+    eprintln = capture.eprintln,
+    open_file_with = fs.open_file_with,
+    read_file_to_string = fs.read_file_to_string,
+    read_file_to_array = fs.read_file_to_array,
+    read_char = refuse "`read_char`",
+    read_line = refuse "`read_line`",
+    load_file = \_ -> refuse "`load_file`",
+    run_expr = \_ -> refuse "`run_expr`"
+}
+"#;
+
+/// Replaces `std.random.prim` in the sandbox. The functions which use a random generator of the
+/// host use the generator seeded by the evaluation instead (see `random`), the seeded
+/// `XorShiftRng` is the real implementation which is registered as `std.random.host_prim`. Like
+/// `std.io.host_prim` it can not be imported by snippets, as its generator would make results
+/// which are reported as deterministic differ between evaluations.
+const RANDOM_PRIM: &str = r#"//@NO-IMPLICIT-PRELUDE
+let prim @ { XorShiftRng } = import! std.random.host_prim
+let seeded = import! try.random
+{
+    XorShiftRng,
+    xor_shift_new = prim.xor_shift_new,
+    xor_shift_next = prim.xor_shift_next,
+    next_int = seeded.next_int,
+    next_float = seeded.next_float,
+    gen_int_range = seeded.gen_int_range
+}
+"#;
+
+/// Primitive modules which the sandbox replaces by a version that can not reach the host.
+pub(crate) const SANDBOXED_PRIMS: &[(&str, &str)] =
+    &[("std.io.prim", IO_PRIM), ("std.random.prim", RANDOM_PRIM)];
+
+/// Lists the primitive modules which the sandbox loads, either from the host or as their sandboxed
+/// replacement. Modules which are not allowed fail to import, except for `std.process.prim` which
+/// is replaced by a version that refuses to run anything.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    pub allowed_prims: BTreeSet<String>,
+}
+
+impl Default for SandboxPolicy {
+    /// Allows every module that does not give access to the host
+    fn default() -> Self {
+        SandboxPolicy {
+            allowed_prims: PURE_PRIMS
+                .iter()
+                .map(|&(name, _)| name)
+                .chain(SANDBOXED_PRIMS.iter().map(|&(name, _)| name))
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+impl SandboxPolicy {
+    pub fn allows(&self, module: &str) -> bool {
+        self.allowed_prims.contains(module)
+    }
+}
+
+pub(crate) fn add_prim_modules(vm: &Thread, policy: &SandboxPolicy) -> crate::Result<()> {
+    for &(name, load) in PURE_PRIMS.iter().chain(HOST_PRIMS) {
+        if policy.allows(name) {
+            add_extern_module(vm, name, load);
+        } else if name == "std.process.prim" {
+            add_extern_module(vm, name, load_process_stub);
+        } else {
+            refuse_module(vm, name);
+        }
+    }
+
+    add_extern_module(vm, "std.io.host_prim", crate::std_lib::io::load);
+    add_extern_module(vm, "std.random.host_prim", crate::std_lib::random::load);
+    add_extern_module(vm, "try.io", capture::load);
+    add_extern_module(vm, "try.fs", load_virtual_fs);
+    add_extern_module(vm, "try.random", random::load);
+    add_extern_module(vm, "try.sandbox", load_refusals);
+    for &(name, source) in SANDBOXED_PRIMS {
+        if policy.allows(name) {
+            vm.load_script(name, source)?;
+        } else {
+            refuse_module(vm, name);
+        }
+    }
+
+    add_extern_module(vm, "gluon.try.display", display::load);
+
+    Ok(())
+}
+
+/// The suffix of the modules holding the host implementations which the sandboxed modules are
+/// built from.
+const HOST_PRIM: &str = "host_prim";
+
+/// Refuses `source` if it could import one of the `*.host_prim` modules.
+///
+/// They have to be importable by `IO_PRIM` and `RANDOM_PRIM`, which are compiled before any
+/// snippet, so snippets are checked instead. A module is imported either by its path, in which
+/// case `host_prim` is written out in the source, or by a string whose escapes could hide the
+/// name, so importing by string is refused as well.
+pub(crate) fn check_imports(file: &str, source: &str) -> Result<(), Diagnostic> {
+    let refuse = |message: String| {
+        Err(Diagnostic {
+            file: file.into(),
+            span: None,
+            message,
+        })
+    };
+    if source.contains(HOST_PRIM) {
+        return refuse(format!(
+            "`{}` modules are not available in the sandbox",
+            HOST_PRIM
+        ));
+    }
+    for (i, _) in source.match_indices("import!") {
+        if skip_trivia(&source[i + "import!".len()..]).starts_with('"') {
+            return refuse("Modules can only be imported by their path in the sandbox".into());
+        }
+    }
+    Ok(())
+}
+
+/// Skips the whitespace, comments and parentheses at the start of `source`.
+fn skip_trivia(mut source: &str) -> &str {
+    loop {
+        let trimmed = source.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+        source = if let Some(comment) = trimmed.strip_prefix("//") {
+            comment.find('\n').map_or("", |end| &comment[end..])
+        } else if let Some(comment) = trimmed.strip_prefix("/*") {
+            comment.find("*/").map_or("", |end| &comment[end + 2..])
+        } else {
+            return trimmed;
+        };
+    }
+}
+
+/// Makes importing `name` fail.
+fn refuse_module(vm: &Thread, name: &'static str) {
+    add_extern_module(vm, name, move |_: &Thread| {
+        Err(vm::Error::Message(format!(
+            "`{}` is not available in the sandbox",
+            name
+        )))
+    });
+}
+
+thread_local! {
+    static FILES: RefCell<BTreeMap<String, String>> = RefCell::new(BTreeMap::new());
+}
+
+/// Runs `f` with `files` as the contents of the virtual filesystem of this thread.
+pub(crate) fn with_files<R>(files: &BTreeMap<String, String>, f: impl FnOnce() -> R) -> R {
+    FILES.with(|vfs| *vfs.borrow_mut() = files.clone());
+    let result = f();
+    FILES.with(|vfs| vfs.borrow_mut().clear());
+    result
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let path = path.trim_start_matches("./");
+    FILES
+        .with(|vfs| vfs.borrow().get(path).cloned())
+        .ok_or_else(|| format!("No such file in the sandbox: `{}`", path))
+}
+
+fn read_file_to_string(path: &str) -> IO<String> {
+    match read_file(path) {
+        Ok(contents) => IO::Value(contents),
+        Err(err) => IO::Exception(err),
+    }
+}
+
+fn read_file_to_array(path: &str) -> IO<Vec<u8>> {
+    match read_file(path) {
+        Ok(contents) => IO::Value(contents.into_bytes()),
+        Err(err) => IO::Exception(err),
+    }
+}
+
+fn open_file_with(path: &str, _: OpaqueValue<RootedThread, A>) -> IO<OpaqueValue<RootedThread, A>> {
+    IO::Exception(format!(
+        "Unable to open `{}`: only `read_file_to_string` and `read_file_to_array` may access \
+         files in the sandbox",
+        path
+    ))
+}
+
+fn load_virtual_fs(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            open_file_with => primitive!(2, "try.fs.open_file_with", open_file_with),
+            read_file_to_string => {
+                primitive!(1, "try.fs.read_file_to_string", read_file_to_string)
+            },
+            read_file_to_array => primitive!(1, "try.fs.read_file_to_array", read_file_to_array)
+        },
+    )
+}
+
+fn execute(_: OpaqueValue<RootedThread, A>) -> IO<Option<i32>> {
+    IO::Exception("Running processes is not available in the sandbox".into())
+}
+
+/// Fails with an error saying that `what` is not available, in place of an `IO` action which would
+/// reach the host.
+fn refuse(what: &str) -> IO<OpaqueValue<RootedThread, A>> {
+    IO::Exception(format!("{} is not available in the sandbox", what))
+}
+
+fn load_refusals(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            refuse => primitive!(1, "try.sandbox.refuse", refuse)
+        },
+    )
+}
+
+fn load_process_stub(thread: &Thread) -> vm::Result<ExternModule> {
+    ExternModule::new(
+        thread,
+        record! {
+            execute => primitive!(1, "std.process.prim.execute", execute)
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::{EvalResult, Limits, Phase};
+
+    /// Asserts that the evaluation failed in `phase` because the sandbox refused the access.
+    fn assert_refused(result: &EvalResult, phase: Phase, message: &str) {
+        assert_eq!(result.phase, Some(phase), "{:?}", result);
+        assert!(
+            result
+                .diagnostics
+                .iter()
+                .any(|diagnostic| diagnostic.message.contains(message)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn processes_are_refused() {
+        let marker = std::env::temp_dir().join("try_gluon_sandbox_process_marker");
+        let _ = std::fs::remove_file(&marker);
+
+        let result = crate::test_eval_source(
+            &format!(
+                r#"
+                let process = import! std.process
+                process.execute (process.proc "touch" ["{}"])
+                "#,
+                marker.display()
+            ),
+            &Limits::default(),
+            |_| (),
+        );
+
+        assert_refused(&result, Phase::Runtime, "is not available in the sandbox");
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn host_filesystem_is_not_available() {
+        let result = crate::test_eval_source(
+            r#"
+            let fs = import! std.fs
+            fs.read_dir "/"
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_refused(
+            &result,
+            Phase::Parse,
+            "`std.fs.prim` is not available in the sandbox",
+        );
+
+        let result = crate::test_eval_source(
+            r#"
+            let io = import! std.io
+            io.read_file_to_string "Cargo.toml"
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_refused(&result, Phase::Runtime, "No such file in the sandbox");
+    }
+
+    #[test]
+    fn stdin_is_not_available() {
+        for reader in &["read_line", "read_char"] {
+            let result = crate::test_eval_source(
+                &format!("let io = import! std.io\nio.{}", reader),
+                &Limits::default(),
+                |_| (),
+            );
+            let message = format!("`{}` is not available in the sandbox", reader);
+            assert_refused(&result, Phase::Runtime, &message);
+        }
+    }
+
+    #[test]
+    fn host_io_is_not_importable() {
+        let result = crate::test_eval_source(
+            r#"
+            let prim = import! std.io.host_prim
+            prim.read_line
+            "#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_refused(&result, Phase::Parse, "are not available in the sandbox");
+
+        let result = crate::test_eval_source(
+            r#"import! "std/io/host\u{5f}prim.glu""#,
+            &Limits::default(),
+            |_| (),
+        );
+        assert_refused(&result, Phase::Parse, "only be imported by their path");
+
+        assert!(check_imports("<top>", "import! /* */ (std.io)").is_ok());
+        assert!(check_imports("<top>", "import! // \n \"std/io.glu\"").is_err());
+    }
+
+    #[test]
+    fn read_virtual_file() {
+        let result = crate::test_eval_source(
+            r#"
+            let io = import! std.io
+            io.read_file_to_string "data.txt"
+            "#,
+            &Limits::default(),
+            |request| {
+                request
+                    .files
+                    .insert("data.txt".into(), "virtual contents".into());
+            },
+        );
+        assert_eq!(
+            result.value.as_deref(),
+            Some(r#""virtual contents""#),
+            "{:?}",
+            result
+        );
+    }
+}
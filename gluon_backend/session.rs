@@ -0,0 +1,195 @@
+//! REPL sessions which keep the bindings of earlier inputs, like `gluon_repl`.
+//!
+//! An input which only declares bindings (`let x = ...`, `type T = ...`) is loaded as a module of
+//! the session's VM which exports everything it declared. Later inputs are prefixed with an
+//! import of those modules so that each binding is only evaluated once, its side effects are not
+//! repeated by later inputs.
+
+use std::result::Result as StdResult;
+
+use try_gluon_api::{Cancellation, Diagnostic, EvalRequest, EvalResult, Limits, Phase};
+
+use crate::{project::Module, EvalVm, Result};
+
+pub struct Session {
+    vm: EvalVm,
+    /// Imports the bindings of every earlier input, one line per input
+    prefix: String,
+    prefix_lines: usize,
+    inputs: usize,
+}
+
+impl Session {
+    /// Creates a session with a VM of its own, using the same sandbox policy as `vm`.
+    pub fn new(vm: &EvalVm) -> Result<Session> {
+        Ok(Session {
+            vm: vm.isolated()?,
+            prefix: String::new(),
+            prefix_lines: 0,
+            inputs: 0,
+        })
+    }
+
+    pub fn eval(
+        &mut self,
+        request: &EvalRequest,
+        limits: &Limits,
+        cancellation: &Cancellation,
+    ) -> EvalResult {
+        let names = match declarations(&request.source) {
+            Ok(names) => names,
+            Err(diagnostic) => return EvalResult::failure(Phase::Parse, vec![diagnostic]),
+        };
+        let source = format!("{}{}", self.prefix, request.source);
+        let prefix_lines = self.prefix_lines;
+
+        let mut result = match names {
+            None => crate::eval_with_modules(
+                &self.vm,
+                &[],
+                &EvalRequest {
+                    source,
+                    ..request.clone()
+                },
+                limits,
+                cancellation,
+                Box::new(|_| ()),
+            ),
+            Some(names) => {
+                let module = format!("repl.input{}", self.inputs);
+                self.inputs += 1;
+
+                let exports = format!("{{ {} }}", names.join(", "));
+                let module_source = format!("{}\n{}", source, exports);
+                let result = crate::eval_with_modules(
+                    &self.vm,
+                    &[Module {
+                        name: module.clone(),
+                        file: "<top>",
+                        source: &module_source,
+                    }],
+                    &EvalRequest {
+                        source: format!("import! {}", module),
+                        ..request.clone()
+                    },
+                    limits,
+                    cancellation,
+                    Box::new(|_| ()),
+                );
+                if result.value.is_some() {
+                    self.prefix += &format!("let {} = import! {} in\n", exports, module);
+                    self.prefix_lines += 1;
+                }
+                result
+            }
+        };
+
+        // Report errors relative to the input rather than to the source with the imports
+        for diagnostic in &mut result.diagnostics {
+            if diagnostic.file.starts_with("repl.") {
+                diagnostic.file = "<top>".into();
+            }
+            if let Some(span) = &mut diagnostic.span {
+                for position in [&mut span.start, &mut span.end] {
+                    position.line = position.line.saturating_sub(prefix_lines).max(1);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Returns the names declared by `source` if it consists only of declarations, `None` if it is an
+/// expression.
+///
+/// Gluon's layout rules make every line which starts in the first column either a declaration or
+/// the expression the declarations are in scope of.
+fn declarations(source: &str) -> StdResult<Option<Vec<String>>, Diagnostic> {
+    let mut names = Vec::new();
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if line.starts_with(char::is_whitespace)
+            || trimmed.is_empty()
+            || trimmed.starts_with("//")
+            || trimmed.starts_with("#[")
+            || trimmed == "rec"
+        {
+            continue;
+        }
+
+        let mut words = trimmed.split_whitespace();
+        let keyword = match words.next() {
+            Some("rec") => words.next(),
+            keyword => keyword,
+        };
+        if !matches!(keyword, Some("let") | Some("type")) {
+            return Ok(None);
+        }
+
+        let word = words.next().unwrap_or("");
+        let name: String = word
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || *c == '_')
+            .collect();
+        if !name.starts_with(|c: char| c.is_alphabetic() || c == '_') {
+            return Err(Diagnostic {
+                file: "<top>".into(),
+                span: None,
+                message: format!(
+                    "Only named bindings can be kept in a session, `{}` binds a pattern",
+                    trimmed
+                ),
+            });
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    Ok(if names.is_empty() { None } else { Some(names) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_declarations() {
+        assert_eq!(
+            declarations("let x = 1\n\ntype Foo = Int\nlet f y =\n    y\n"),
+            Ok(Some(vec!["x".into(), "Foo".into(), "f".into()]))
+        );
+        assert_eq!(declarations("let x = 1\nx + 1"), Ok(None));
+        assert_eq!(declarations("1 + 2"), Ok(None));
+        assert!(declarations("let { x } = { x = 1 }").is_err());
+    }
+
+    #[test]
+    fn bindings_are_kept() {
+        let mut session = Session::new(crate::test_vm()).unwrap();
+        let mut eval = |source: &str| {
+            session.eval(
+                &EvalRequest {
+                    source: source.into(),
+                    ..EvalRequest::default()
+                },
+                &Limits::default(),
+                &Cancellation::default(),
+            )
+        };
+
+        let result = eval("let x = 1\nlet add a b = a + b");
+        assert!(result.value.is_some(), "{:?}", result);
+
+        let result = eval("add x 2");
+        assert_eq!(result.value.as_deref(), Some("3"), "{:?}", result);
+
+        let result = eval("y");
+        assert_eq!(result.phase, Some(Phase::Typecheck), "{:?}", result);
+        assert_eq!(
+            result.diagnostics[0].span.map(|span| span.start.line),
+            Some(1),
+            "{:?}",
+            result
+        );
+    }
+}
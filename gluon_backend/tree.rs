@@ -0,0 +1,250 @@
+//! Renders values as a `ValueTree`, guided by their type in the same way as `ValuePrinter` but
+//! into a structure a client can display without parsing gluon.
+
+use std::convert::TryFrom;
+
+use try_gluon_api::{TreeField, TreeOptions, ValueTree};
+
+use gluon::{
+    base::{
+        resolve,
+        types::{arg_iter, ArcType, BuiltinType, NullInterner, Type, TypeEnv, TypeExt},
+    },
+    vm::{
+        api::{ArrayRef, Data, ValueRef},
+        Variants,
+    },
+};
+
+/// The deepest a tree is rendered whatever the request asks for, as every level recurses on the
+/// native stack of the evaluating thread.
+const MAX_DEPTH: usize = 64;
+/// The most fields or elements rendered of any value, whatever the request asks for.
+const MAX_WIDTH: usize = 1000;
+
+/// Renders `value` of type `typ`, resolving the aliases in `typ` through `env`.
+pub(crate) fn render(
+    env: &dyn TypeEnv<Type = ArcType>,
+    typ: &ArcType,
+    value: Variants<'_>,
+    options: &TreeOptions,
+) -> ValueTree {
+    let options = TreeOptions {
+        max_depth: options.max_depth.min(MAX_DEPTH),
+        max_width: options.max_width.min(MAX_WIDTH),
+    };
+    Renderer {
+        env,
+        options: &options,
+    }
+    .render(typ, value, 0)
+}
+
+struct Renderer<'a> {
+    env: &'a dyn TypeEnv<Type = ArcType>,
+    options: &'a TreeOptions,
+}
+
+impl Renderer<'_> {
+    fn render(&self, typ: &ArcType, value: Variants<'_>, depth: usize) -> ValueTree {
+        let name = typ.to_string();
+        if depth >= self.options.max_depth {
+            return ValueTree::Truncated { typ: name };
+        }
+        // Aliases such as `Option Int` must be resolved to find the fields or constructors
+        let resolved = resolve::remove_aliases_cow(self.env, &mut NullInterner, typ);
+        if resolved.as_function().is_some() {
+            return ValueTree::Function { typ: name };
+        }
+
+        match value.as_ref() {
+            ValueRef::Byte(value) => ValueTree::Byte { value },
+            // Characters are stored as integers
+            ValueRef::Int(value) => match &**resolved {
+                Type::Builtin(BuiltinType::Char) => u32::try_from(value)
+                    .ok()
+                    .and_then(std::char::from_u32)
+                    .map_or(ValueTree::Int { value }, |value| ValueTree::Char { value }),
+                _ => ValueTree::Int { value },
+            },
+            ValueRef::Float(value) => ValueTree::Float { value },
+            ValueRef::String(value) => ValueTree::String {
+                value: value.into(),
+            },
+            ValueRef::Array(array) => self.array(name, &resolved, array, depth),
+            ValueRef::Data(data) => match &**resolved {
+                Type::Record(_) => self.record(name, &resolved, data, depth),
+                Type::Variant(_) => self.variant(name, &resolved, data, depth),
+                _ => ValueTree::Opaque { typ: name },
+            },
+            ValueRef::Closure(_) => ValueTree::Function { typ: name },
+            _ => ValueTree::Opaque { typ: name },
+        }
+    }
+
+    fn record(&self, name: String, typ: &ArcType, data: Data<'_>, depth: usize) -> ValueTree {
+        let field_types: Vec<_> = typ.row_iter().collect();
+        let fields = field_types
+            .iter()
+            .zip(data_fields(data))
+            .take(self.options.max_width)
+            .map(|(field, value)| TreeField {
+                name: field.name.declared_name().into(),
+                value: self.render(&field.typ, value, depth + 1),
+            })
+            .collect();
+        ValueTree::Record {
+            typ: name,
+            fields,
+            omitted: field_types.len().saturating_sub(self.options.max_width),
+        }
+    }
+
+    fn variant(&self, name: String, typ: &ArcType, data: Data<'_>, depth: usize) -> ValueTree {
+        let constructor = match typ.row_iter().nth(data.tag() as usize) {
+            Some(constructor) => constructor,
+            None => return ValueTree::Opaque { typ: name },
+        };
+        let args = arg_iter(&constructor.typ)
+            .zip(data_fields(data))
+            .take(self.options.max_width)
+            .map(|(typ, value)| self.render(typ, value, depth + 1))
+            .collect();
+        ValueTree::Variant {
+            typ: name,
+            constructor: constructor.name.declared_name().into(),
+            args,
+            omitted: data.len().saturating_sub(self.options.max_width),
+        }
+    }
+
+    fn array(&self, name: String, typ: &ArcType, array: ArrayRef<'_>, depth: usize) -> ValueTree {
+        let element = match &**typ {
+            Type::App(_, args) if !args.is_empty() => args[0].clone(),
+            _ => Type::hole(),
+        };
+        let elements = array
+            .iter()
+            .take(self.options.max_width)
+            .map(|value| self.render(&element, value, depth + 1))
+            .collect();
+        ValueTree::Array {
+            typ: name,
+            elements,
+            omitted: array.len().saturating_sub(self.options.max_width),
+        }
+    }
+}
+
+fn data_fields(data: Data<'_>) -> impl Iterator<Item = Variants<'_>> {
+    (0..data.len()).filter_map(move |index| data.get_variant(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use try_gluon_api::Limits;
+
+    #[test]
+    fn render_values() {
+        let options = TreeOptions {
+            max_depth: 6,
+            max_width: 2,
+        };
+        let result = crate::test_eval_source(
+            r#"{ n = 1, c = 'a', xs = [1.5, 2.5, 3.5], o = Some "x", f = \x -> x + 1 }"#,
+            &Limits::default(),
+            |request| request.tree = Some(options),
+        );
+        let fields = match result.tree {
+            Some(ValueTree::Record {
+                fields, omitted, ..
+            }) => {
+                assert_eq!(omitted, 3);
+                fields
+            }
+            _ => panic!("{:?}", result),
+        };
+        assert_eq!(
+            fields,
+            [
+                TreeField {
+                    name: "n".into(),
+                    value: ValueTree::Int { value: 1 },
+                },
+                TreeField {
+                    name: "c".into(),
+                    value: ValueTree::Char { value: 'a' },
+                },
+            ]
+        );
+
+        let result = crate::test_eval_source("[Some 1, None]", &Limits::default(), |request| {
+            request.tree = Some(TreeOptions::default())
+        });
+        match &result.tree {
+            Some(ValueTree::Array { elements, .. }) => match &elements[..] {
+                [ValueTree::Variant {
+                    constructor: some,
+                    args,
+                    ..
+                }, ValueTree::Variant {
+                    constructor: none, ..
+                }] => {
+                    assert_eq!((&some[..], &none[..]), ("Some", "None"));
+                    assert_eq!(args, &[ValueTree::Int { value: 1 }]);
+                }
+                _ => panic!("{:?}", elements),
+            },
+            _ => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn truncate_deep_values() {
+        let options = TreeOptions {
+            max_depth: 1,
+            ..TreeOptions::default()
+        };
+        let result = crate::test_eval_source(
+            r#"{ f = \x -> x, xs = [[1]] }"#,
+            &Limits::default(),
+            |request| request.tree = Some(options),
+        );
+        match &result.tree {
+            Some(ValueTree::Record { fields, .. }) => {
+                assert!(matches!(fields[0].value, ValueTree::Truncated { .. }));
+                assert!(matches!(fields[1].value, ValueTree::Truncated { .. }));
+            }
+            _ => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn depth_is_bounded_by_the_server() {
+        let options = TreeOptions {
+            max_depth: 1_000_000,
+            ..TreeOptions::default()
+        };
+        let result = crate::test_eval_source(
+            r#"
+            type Deep = | Leaf | Node Deep
+            let build n = if n == 0 then Leaf else Node (build (n - 1))
+            build 200
+            "#,
+            &Limits::default(),
+            |request| request.tree = Some(options),
+        );
+        let mut tree = result.tree.unwrap_or_else(|| panic!("{:?}", result));
+        let mut depth = 0;
+        while let ValueTree::Variant { mut args, .. } = tree {
+            tree = args
+                .pop()
+                .unwrap_or_else(|| panic!("Reached the leaf at depth {}", depth));
+            depth += 1;
+        }
+        assert!(matches!(tree, ValueTree::Truncated { .. }), "{:?}", tree);
+        assert_eq!(depth, MAX_DEPTH);
+    }
+}
@@ -7,11 +7,16 @@ cp bootstrap target/
 
 mkdir -p target/target
 
+# Compile the std prelude of every backend ahead of time so a cold start only loads it
+rm -rf target/prelude
+target/x86_64-unknown-linux-gnu/release/try_gluon --build-prelude-cache --prelude-cache target/prelude
+
 zip --recurse-paths target/lambda.zip \
   bootstrap \
   Cargo.lock \
   public \
   target/dist \
+  target/prelude \
   src
 
 cp -r target/x86_64-unknown-linux-gnu/release/try_gluon target/
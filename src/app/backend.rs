@@ -0,0 +1,894 @@
+//! The versions of gluon which snippets can be evaluated with.
+//!
+//! Every backend is a crate (`gluon_master`, `gluon_crates_io`) which compiles the source in
+//! `gluon_backend` against a different version of gluon. Adding a version means adding such a
+//! crate and registering it in `BACKENDS`, after which it is routed at `/try/<name>/...`.
+
+use std::{
+    any::Any,
+    fmt, fs,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use {anyhow::anyhow, futures::prelude::*, serde::Serialize};
+
+use gluon_codegen::{Pushable, Trace, Userdata, VmType};
+
+use try_gluon_api::{
+    Cancellation, CheckResult, Completions, CursorRequest, Definition, Diagnostic, EvalRequest,
+    EvalResult, ExplainRequest, Explanation, Hover, LimitExceeded, LimitKind, Limits, NewSession,
+    OutputSink, Phase, SignatureHelp,
+};
+
+use gluon::{
+    vm::{self, primitive, record, ExternModule},
+    Thread,
+};
+
+use crate::{
+    cache::{self, Cache},
+    limiter::{Limiter, Overloaded, Slot},
+    metrics,
+    session::{Session, Sessions},
+    worker, Opts, Result,
+};
+
+/// The operations a version of gluon provides to the server.
+pub trait Backend: fmt::Debug + Send + Sync + 'static {
+    fn eval(
+        &self,
+        request: &EvalRequest,
+        limits: &Limits,
+        cancellation: &Cancellation,
+    ) -> EvalResult;
+    /// Like `eval` but passes the output to `on_output` while the snippet is running.
+    fn eval_streaming(
+        &self,
+        request: &EvalRequest,
+        limits: &Limits,
+        cancellation: &Cancellation,
+        on_output: OutputSink,
+    ) -> EvalResult;
+    fn check(&self, source: &str) -> CheckResult;
+    fn explain(&self, request: &ExplainRequest) -> Explanation;
+    fn complete(&self, request: &CursorRequest) -> Completions;
+    fn hover(&self, request: &CursorRequest) -> Hover;
+    fn signature_help(&self, request: &CursorRequest) -> SignatureHelp;
+    fn definition(&self, request: &CursorRequest) -> Definition;
+    fn format(&self, source: &str) -> Result<String, String>;
+    fn new_session(&self) -> Result<Box<dyn ReplSession>, String>;
+}
+
+/// A REPL session which keeps the bindings of earlier evaluations.
+pub trait ReplSession: Send + 'static {
+    fn eval(
+        &mut self,
+        request: &EvalRequest,
+        limits: &Limits,
+        cancellation: &Cancellation,
+    ) -> EvalResult;
+}
+
+macro_rules! impl_backend {
+    ($backend: ident) => {
+        impl Backend for $backend::EvalVm {
+            fn eval(
+                &self,
+                request: &EvalRequest,
+                limits: &Limits,
+                cancellation: &Cancellation,
+            ) -> EvalResult {
+                $backend::eval(self, request, limits, cancellation)
+            }
+            fn eval_streaming(
+                &self,
+                request: &EvalRequest,
+                limits: &Limits,
+                cancellation: &Cancellation,
+                on_output: OutputSink,
+            ) -> EvalResult {
+                $backend::eval_streaming(self, request, limits, cancellation, on_output)
+            }
+            fn check(&self, source: &str) -> CheckResult {
+                $backend::check(self, source)
+            }
+            fn explain(&self, request: &ExplainRequest) -> Explanation {
+                $backend::explain::explain(self, request)
+            }
+            fn complete(&self, request: &CursorRequest) -> Completions {
+                $backend::ide::complete(self, request)
+            }
+            fn hover(&self, request: &CursorRequest) -> Hover {
+                $backend::ide::hover(self, request)
+            }
+            fn signature_help(&self, request: &CursorRequest) -> SignatureHelp {
+                $backend::ide::signature_help(self, request)
+            }
+            fn definition(&self, request: &CursorRequest) -> Definition {
+                $backend::ide::definition(self, request)
+            }
+            fn format(&self, source: &str) -> Result<String, String> {
+                $backend::format_expr(self, source)
+            }
+            fn new_session(&self) -> Result<Box<dyn ReplSession>, String> {
+                match $backend::session::Session::new(self) {
+                    Ok(session) => Ok(Box::new(session)),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+        }
+
+        impl ReplSession for $backend::session::Session {
+            fn eval(
+                &mut self,
+                request: &EvalRequest,
+                limits: &Limits,
+                cancellation: &Cancellation,
+            ) -> EvalResult {
+                $backend::session::Session::eval(self, request, limits, cancellation)
+            }
+        }
+    };
+}
+
+impl_backend!(gluon_master);
+impl_backend!(gluon_crates_io);
+
+struct Registration {
+    /// The name the backend is selected by (`/try/<name>/eval`)
+    name: &'static str,
+    /// The crate implementing the backend, used to find its version of gluon in `Cargo.lock`
+    krate: &'static str,
+    load: fn(&BackendInfo, &LoadOptions) -> Result<Arc<dyn Backend>>,
+}
+
+/// How the VM of a backend is created.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    /// The number of threads to keep ready to evaluate on
+    pub pool_size: usize,
+    /// The directory the compiled std prelude is persisted in between restarts
+    pub prelude_cache: Option<PathBuf>,
+}
+
+macro_rules! registration {
+    ($name: expr, $backend: ident) => {
+        Registration {
+            name: $name,
+            krate: stringify!($backend),
+            load: |info, options| {
+                let policy = $backend::SandboxPolicy::default();
+                let vm = match &options.prelude_cache {
+                    // The cache can not be invalidated if the version is unknown
+                    Some(dir) if info.version != UNKNOWN_VERSION => {
+                        let cache = $backend::PreludeCache {
+                            dir: dir.clone(),
+                            version: info.version.clone(),
+                        };
+                        let (vm, prelude) =
+                            $backend::make_eval_vm_with_prelude_cache(&policy, &cache)?;
+                        log::info!("{} ({}): {}", info.name, info.version, prelude);
+                        vm
+                    }
+                    _ => $backend::make_eval_vm(&policy)?,
+                };
+                Ok(Arc::new(vm.with_thread_pool(
+                    options.pool_size,
+                    metrics::thread_pool_miss,
+                )))
+            },
+        }
+    };
+}
+
+const BACKENDS: &[Registration] = &[
+    registration!("released", gluon_crates_io),
+    registration!("master", gluon_master),
+];
+
+fn registration(name: &str) -> Result<&'static Registration> {
+    BACKENDS
+        .iter()
+        .find(|registration| registration.name == name)
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown backend `{}`, expected one of: {}",
+                name,
+                BACKENDS
+                    .iter()
+                    .map(|registration| registration.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// Creates a VM for the backend described by `info`.
+pub fn load(info: &BackendInfo, options: &LoadOptions) -> Result<Arc<dyn Backend>> {
+    (registration(&info.name)?.load)(info, options)
+}
+
+/// The version of a backend which could not be found in `Cargo.lock`.
+const UNKNOWN_VERSION: &str = "unknown";
+
+#[derive(Clone, Debug, Serialize, Pushable, VmType)]
+pub struct BackendInfo {
+    pub name: String,
+    /// The version of gluon, or the git revision if it is built from git
+    pub version: String,
+}
+
+impl BackendInfo {
+    fn new(registration: &Registration, lock_file: Option<&toml::Value>) -> BackendInfo {
+        BackendInfo {
+            name: registration.name.into(),
+            version: lock_file
+                .and_then(|lock_file| gluon_version(lock_file, registration.krate))
+                .unwrap_or_else(|| UNKNOWN_VERSION.into()),
+        }
+    }
+}
+
+/// Looks up the version information of the backend called `name`.
+pub fn info(name: &str) -> Result<BackendInfo> {
+    Ok(BackendInfo::new(
+        registration(name)?,
+        read_lock_file().as_ref(),
+    ))
+}
+
+/// Runs `f`, turning a panic in the compiler or VM into an "internal compiler error" message.
+fn catch_panic<T>(info: &BackendInfo, f: impl FnOnce() -> T) -> Result<T, String> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        format!(
+            "Internal compiler error in gluon {} ({}): {}",
+            info.version,
+            info.name,
+            panic_message(&*payload)
+        )
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| &s[..]))
+        .unwrap_or("Unknown panic")
+}
+
+/// Evaluates `request` on `backend`, reporting a panic as a failure in `Phase::InternalError`.
+pub fn eval_catching_panics(
+    backend: &dyn Backend,
+    info: &BackendInfo,
+    request: &EvalRequest,
+    limits: &Limits,
+    cancellation: &Cancellation,
+) -> EvalResult {
+    catching_panics(info, || backend.eval(request, limits, cancellation))
+}
+
+/// Runs the evaluation `f`, reporting a panic as a failure in `Phase::InternalError`.
+pub fn catching_panics(info: &BackendInfo, f: impl FnOnce() -> EvalResult) -> EvalResult {
+    catch_panic(info, f).unwrap_or_else(|message| {
+        EvalResult::failure(
+            Phase::InternalError,
+            vec![Diagnostic {
+                file: "<top>".into(),
+                span: None,
+                message,
+            }],
+        )
+    })
+}
+
+/// A backend along with the limits and workers the server evaluates with.
+#[derive(Clone, Debug, VmType, Userdata, Trace)]
+#[gluon(vm_type = "TryBackend")]
+#[gluon_userdata(clone)]
+#[gluon_trace(skip)]
+pub struct TryBackend {
+    pub info: BackendInfo,
+    pub backend: Arc<dyn Backend>,
+    max_limits: Limits,
+    /// Evaluates in worker processes instead of on `backend` when set
+    workers: Option<Arc<worker::Pool>>,
+    /// The REPL sessions of every backend
+    sessions: Arc<Sessions>,
+    /// The cached results of every backend
+    cache: Arc<Cache>,
+    /// Bounds the number of evaluations of every backend which run at once
+    limiter: Arc<Limiter>,
+}
+
+impl TryBackend {
+    fn eval(&self, request: &EvalRequest, cancellation: &Cancellation) -> EvalResult {
+        self.eval_streaming(request, cancellation, Box::new(|_| ()))
+    }
+
+    /// Evaluates `request`, passing the output to `on_output` as it is printed. Results from the
+    /// cache and from worker processes only have their output passed on once they are complete.
+    pub fn eval_streaming(
+        &self,
+        request: &EvalRequest,
+        cancellation: &Cancellation,
+        mut on_output: OutputSink,
+    ) -> EvalResult {
+        if let Some(result) = self.cached_eval(request) {
+            result.output.iter().for_each(&mut on_output);
+            return result;
+        }
+        metrics::cache_miss();
+
+        let limits = self.max_limits.clamp(&request.limits);
+
+        let result = match &self.workers {
+            Some(workers) => {
+                let result = workers.eval(request, &limits, cancellation);
+                result.output.iter().for_each(&mut on_output);
+                result
+            }
+            None => catching_panics(&self.info, || {
+                self.backend
+                    .eval_streaming(request, &limits, cancellation, on_output)
+            }),
+        };
+        record_result(&result);
+        let key = cache::Key::new(&self.info, (request.clone(), limits));
+        self.cache.insert_eval(key, &result);
+        result
+    }
+
+    /// The cached result of `request`, if any. Checked before waiting for a slot to evaluate in
+    /// so that cached results are returned even when the server is overloaded.
+    pub fn cached_eval(&self, request: &EvalRequest) -> Option<EvalResult> {
+        let limits = self.max_limits.clamp(&request.limits);
+        let key = cache::Key::new(&self.info, (request.clone(), limits));
+        let result = self.cache.get_eval(&key)?;
+        metrics::cache_hit();
+        Some(result)
+    }
+
+    fn format(&self, source: &str) -> Result<String, String> {
+        if let Some(result) = self.cached_format(source) {
+            return result;
+        }
+        metrics::cache_miss();
+
+        let key = cache::Key::new(&self.info, source.to_string());
+
+        match catch_panic(&self.info, || self.backend.format(source)) {
+            Ok(result) => {
+                self.cache.insert_format(key, result.clone());
+                result
+            }
+            Err(message) => {
+                metrics::internal_error();
+                Err(message)
+            }
+        }
+    }
+
+    /// The cached result of formatting `source`, if any.
+    fn cached_format(&self, source: &str) -> Option<Result<String, String>> {
+        let key = cache::Key::new(&self.info, source.to_string());
+        let result = self.cache.get_format(&key)?;
+        metrics::cache_hit();
+        Some(result)
+    }
+
+    /// Creates a REPL session. Sessions always evaluate in the server process as their state
+    /// lives in the session's VM.
+    fn create_session(&self) -> JsonResponse {
+        if self.sessions.is_full() {
+            return JsonResponse::error(503, "Too many sessions are open, try again later");
+        }
+        let repl = match catch_panic(&self.info, || self.backend.new_session()).and_then(|x| x) {
+            Ok(repl) => repl,
+            Err(message) => return JsonResponse::error(500, message),
+        };
+        let session = Session::new(self.info.clone(), self.max_limits, repl);
+        match self.sessions.insert(session) {
+            Some(id) => JsonResponse::ok(&NewSession { id }),
+            None => JsonResponse::error(503, "Too many sessions are open, try again later"),
+        }
+    }
+
+    /// Evaluates in the session at `path` (`/try/session/<id>/eval`), which may belong to any
+    /// backend.
+    fn session_eval(&self, path: &str, body: &str, cancellation: &Cancellation) -> JsonResponse {
+        let session = match self.sessions.get(session_id(path)) {
+            Some(session) => session,
+            None => return JsonResponse::error(404, "The session does not exist or has expired"),
+        };
+        let request = match EvalRequest::parse(body) {
+            Ok(request) => request,
+            Err(message) => return JsonResponse::error(400, message),
+        };
+        let result = session.eval(&request, cancellation);
+        record_result(&result);
+        JsonResponse::ok(&result)
+    }
+
+    /// Deletes the session at `path` (`/try/session/<id>`).
+    fn delete_session(&self, path: &str) -> JsonResponse {
+        if self.sessions.remove(session_id(path)) {
+            JsonResponse {
+                status: 204,
+                body: String::new(),
+                retry_after: None,
+            }
+        } else {
+            JsonResponse::error(404, "The session does not exist or has expired")
+        }
+    }
+
+    /// Waits for a slot to evaluate in, see `Limiter::acquire`.
+    pub async fn acquire_slot(&self) -> Result<Slot, Overloaded> {
+        self.limiter.acquire().await
+    }
+
+    /// Runs the analysis `f` on a blocking thread once the limiter lets it, like an evaluation as
+    /// it compiles the source too. A panic is reported as an internal compiler error.
+    pub async fn analysis<R>(
+        &self,
+        f: impl FnOnce(&dyn Backend) -> R + Send + 'static,
+    ) -> Result<Result<R, String>, Overloaded>
+    where
+        R: Send + 'static,
+    {
+        let backend = self.clone();
+        self.limiter
+            .run(move || {
+                catch_panic(&backend.info, || f(&*backend.backend)).map_err(|message| {
+                    metrics::internal_error();
+                    message
+                })
+            })
+            .await
+    }
+
+    /// Runs `f` on a blocking thread once the limiter lets it, responding with 503 if the server
+    /// is overloaded.
+    async fn limited(
+        &self,
+        f: impl FnOnce(TryBackend) -> JsonResponse + Send + 'static,
+    ) -> JsonResponse {
+        let backend = self.clone();
+        self.limiter
+            .run(move || f(backend))
+            .await
+            .unwrap_or_else(JsonResponse::overloaded)
+    }
+}
+
+/// Records the outcome of an evaluation in the logs and metrics.
+fn record_result(result: &EvalResult) {
+    match result.phase {
+        Some(Phase::InternalError) => metrics::internal_error(),
+        Some(Phase::Cancelled) => {
+            log::info!("Cancelled an evaluation as the client went away");
+            metrics::cancelled();
+        }
+        _ => (),
+    }
+    if let Some(LimitExceeded {
+        kind: LimitKind::Time,
+        limit,
+    }) = result.limit_exceeded
+    {
+        log::warn!("An evaluation exceeded the time limit of {}ms", limit);
+    }
+}
+
+/// Cancels the evaluation when dropped, which happens when the future of a request is dropped
+/// before it completes as the client went away.
+pub struct CancelOnDrop(pub Cancellation);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+fn eval(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    let backend = backend.clone();
+    let request = EvalRequest::parse(body);
+    async move {
+        let cancellation = Cancellation::default();
+        let _cancel_on_drop = CancelOnDrop(cancellation.clone());
+        let request = match request {
+            Ok(request) => request,
+            Err(message) => return JsonResponse::error(400, message),
+        };
+        if let Some(result) = backend.cached_eval(&request) {
+            return JsonResponse::ok(&result);
+        }
+        let eval =
+            move |backend: TryBackend| JsonResponse::ok(&backend.eval(&request, &cancellation));
+        backend.limited(eval).await
+    }
+}
+
+fn format_expr(backend: &TryBackend, source: &str) -> impl Future<Output = JsonResponse> {
+    let backend = backend.clone();
+    let source = source.to_string();
+    async move {
+        let respond = |result: Result<String, String>| match result {
+            Ok(formatted) => JsonResponse::ok(&formatted),
+            Err(message) => JsonResponse::error(500, message),
+        };
+        if let Some(result) = backend.cached_format(&source) {
+            return respond(result);
+        }
+        let format = move |backend: TryBackend| respond(backend.format(&source));
+        backend.limited(format).await
+    }
+}
+
+fn create_session(backend: &TryBackend, _: &str) -> impl Future<Output = JsonResponse> {
+    let backend = backend.clone();
+    async move {
+        // Creating the session's VM takes about as long as an evaluation
+        let create = |backend: TryBackend| backend.create_session();
+        backend.limited(create).await
+    }
+}
+
+fn session_eval(
+    backend: &TryBackend,
+    path: &str,
+    body: &str,
+) -> impl Future<Output = JsonResponse> {
+    let backend = backend.clone();
+    let (path, body) = (path.to_string(), body.to_string());
+    async move {
+        let cancellation = Cancellation::default();
+        let _cancel_on_drop = CancelOnDrop(cancellation.clone());
+        let eval = move |backend: TryBackend| backend.session_eval(&path, &body, &cancellation);
+        backend.limited(eval).await
+    }
+}
+
+/// Runs the analysis `f` of the parsed request, see `TryBackend::analysis`.
+fn analyze<T, R>(
+    backend: &TryBackend,
+    request: Result<T, String>,
+    f: impl FnOnce(&dyn Backend, &T) -> R + Send + 'static,
+) -> impl Future<Output = JsonResponse>
+where
+    T: Send + 'static,
+    R: Serialize + Send + 'static,
+{
+    let backend = backend.clone();
+    async move {
+        let request = match request {
+            Ok(request) => request,
+            Err(message) => return JsonResponse::error(400, message),
+        };
+        match backend.analysis(move |backend| f(backend, &request)).await {
+            Ok(Ok(response)) => JsonResponse::ok(&response),
+            Ok(Err(message)) => JsonResponse::error(500, message),
+            Err(overloaded) => JsonResponse::overloaded(overloaded),
+        }
+    }
+}
+
+fn check(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    analyze(backend, EvalRequest::parse(body), |backend, request| {
+        backend.check(&request.source)
+    })
+}
+
+fn explain(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    analyze(backend, from_json(body), |backend, request| {
+        backend.explain(request)
+    })
+}
+
+fn complete(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    analyze(backend, from_json(body), |backend, request| {
+        backend.complete(request)
+    })
+}
+
+fn hover(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    analyze(backend, from_json(body), |backend, request| {
+        backend.hover(request)
+    })
+}
+
+fn signature_help(backend: &TryBackend, body: &str) -> impl Future<Output = JsonResponse> {
+    analyze(backend, from_json(body), |backend, request| {
+        backend.signature_help(request)
+    })
+}
+
+fn session_id(path: &str) -> &str {
+    let path = path.strip_prefix("/try/session/").unwrap_or("");
+    path.split('/').next().unwrap_or("")
+}
+
+/// A response for the endpoints which respond with other statuses than 200 and 500.
+#[derive(Debug, Pushable, VmType)]
+pub struct JsonResponse {
+    pub status: u16,
+    /// The JSON encoded response if `status` is 200, otherwise an error message
+    pub body: String,
+    /// The number of seconds to wait before retrying, sent as `Retry-After`
+    pub retry_after: Option<u64>,
+}
+
+impl JsonResponse {
+    fn ok<T>(value: &T) -> JsonResponse
+    where
+        T: Serialize,
+    {
+        match to_json(value) {
+            Ok(body) => JsonResponse {
+                status: 200,
+                body,
+                retry_after: None,
+            },
+            Err(message) => JsonResponse::error(500, message),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> JsonResponse {
+        JsonResponse {
+            status,
+            body: message.into(),
+            retry_after: None,
+        }
+    }
+
+    fn overloaded(overloaded: Overloaded) -> JsonResponse {
+        JsonResponse {
+            retry_after: Some(overloaded.retry_after),
+            ..JsonResponse::error(503, "The server is overloaded, try again later")
+        }
+    }
+}
+
+/// The backends enabled by `--backends`, in order (every registered backend if none are listed).
+/// The first one is the default which is also routed without a name (`/try/eval`).
+#[derive(Clone, Debug)]
+pub struct Registry {
+    pub backends: Vec<TryBackend>,
+}
+
+impl Registry {
+    pub fn new(opts: &Opts) -> Result<Registry> {
+        let lock_file = read_lock_file();
+        let sessions = Arc::new(Sessions::new(
+            opts.max_sessions,
+            Duration::from_secs(opts.session_timeout),
+        ));
+        let cache = Arc::new(Cache::new(opts.cache_size));
+        let limiter = Arc::new(Limiter::new(
+            opts.max_concurrent_evals,
+            opts.eval_queue_size,
+            Duration::from_millis(opts.eval_queue_timeout),
+        ));
+
+        let options = LoadOptions {
+            // Snippets are only evaluated on the workers' VMs if there are any
+            pool_size: if opts.workers == 0 {
+                opts.thread_pool_size
+            } else {
+                0
+            },
+            prelude_cache: opts.prelude_cache.as_ref().map(PathBuf::from),
+        };
+
+        let names: Vec<&str> = if opts.backends.is_empty() {
+            BACKENDS
+                .iter()
+                .map(|registration| registration.name)
+                .collect()
+        } else {
+            opts.backends.iter().map(|name| &name[..]).collect()
+        };
+
+        let backends = names
+            .into_iter()
+            .map(|name| {
+                let registration = registration(name)?;
+                let info = BackendInfo::new(registration, lock_file.as_ref());
+                Ok(TryBackend {
+                    backend: (registration.load)(&info, &options)?,
+                    info,
+                    max_limits: opts.limits.to_limits(),
+                    workers: if opts.workers == 0 {
+                        None
+                    } else {
+                        Some(Arc::new(worker::Pool::new(
+                            registration.name,
+                            opts.workers,
+                            opts.prelude_cache.clone(),
+                        )))
+                    },
+                    sessions: sessions.clone(),
+                    cache: cache.clone(),
+                    limiter: limiter.clone(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Registry { backends })
+    }
+
+    pub fn default_backend(&self) -> &TryBackend {
+        &self.backends[0]
+    }
+}
+
+#[cfg(test)]
+impl TryBackend {
+    /// Evaluates on `backend` without workers, caching or a bound on concurrent evaluations.
+    pub fn for_tests(backend: Arc<dyn Backend>) -> TryBackend {
+        TryBackend {
+            info: BackendInfo {
+                name: "test".into(),
+                version: "0.0.0".into(),
+            },
+            backend,
+            max_limits: Limits::default(),
+            workers: None,
+            sessions: Arc::new(Sessions::new(0, Duration::from_secs(60))),
+            cache: Arc::new(Cache::new(0)),
+            limiter: Arc::new(Limiter::new(0, 0, Duration::from_secs(1))),
+        }
+    }
+}
+
+fn read_lock_file() -> Option<toml::Value> {
+    match fs::read_to_string("Cargo.lock") {
+        Ok(contents) => toml::from_str(&contents).ok(),
+        Err(err) => {
+            log::warn!("Unable to read `Cargo.lock`: {}", err);
+            None
+        }
+    }
+}
+
+/// Finds the version of the `gluon` crate which `krate` depends on in a `Cargo.lock` file.
+fn gluon_version(lock_file: &toml::Value, krate: &str) -> Option<String> {
+    fn packages<'a>(
+        lock_file: &'a toml::Value,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a toml::Value> + 'a {
+        lock_file
+            .get("package")
+            .and_then(|packages| packages.as_array())
+            .into_iter()
+            .flatten()
+            .filter(move |package| package.get("name").and_then(|n| n.as_str()) == Some(name))
+    }
+
+    // Dependencies are listed as `gluon` unless there are several versions in the lock file, in
+    // which case they are listed as `gluon <version>` or `gluon <version> (<source>)`
+    let dependency = packages(lock_file, krate)
+        .next()?
+        .get("dependencies")?
+        .as_array()?
+        .iter()
+        .filter_map(|dependency| dependency.as_str())
+        .find(|dependency| dependency.split(' ').next() == Some("gluon"))?;
+    let mut parts = dependency.split(' ');
+    parts.next();
+    let version = parts.next();
+    let source = parts
+        .next()
+        .map(|source| source.trim_matches(&['(', ')'][..]));
+
+    let gluon = packages(lock_file, "gluon").find(|package| {
+        let field = |key| package.get(key).and_then(|value| value.as_str());
+        version.is_none_or(|version| field("version") == Some(version))
+            // The source of git dependencies is listed without the revision
+            && source.is_none_or(|source| field("source").is_some_and(|s| s.starts_with(source)))
+    })?;
+
+    let source = gluon.get("source").and_then(|source| source.as_str());
+    match source.and_then(|source| source.strip_prefix("git+")) {
+        // Use an abbreviated revision for git dependencies, like the client shows for master
+        Some(url) => url
+            .rsplit('#')
+            .next()
+            .map(|revision| revision.chars().take(6).collect()),
+        None => gluon.get("version")?.as_str().map(String::from),
+    }
+}
+
+fn to_json<T>(value: &T) -> Result<String, String>
+where
+    T: Serialize,
+{
+    serde_json::to_string(value).map_err(|err| err.to_string())
+}
+
+fn from_json<T>(s: &str) -> Result<T, String>
+where
+    T: serde::de::DeserializeOwned,
+{
+    serde_json::from_str(s).map_err(|err| format!("Invalid request: {}", err))
+}
+
+/// Loads the `gluon.try` module which gives `server.glu` access to the backends in `registry`.
+pub fn load_module(thread: &Thread, registry: &Registry) -> vm::Result<ExternModule> {
+    thread.register_type::<TryBackend>("TryBackend", &[])?;
+
+    ExternModule::new(
+        thread,
+        record! {
+            backends => registry.backends.clone(),
+            default_backend => registry.default_backend().clone(),
+            info => primitive!(1, "info", |b: &TryBackend| b.info.clone()),
+            eval => primitive!(2, async fn eval),
+            check => primitive!(2, async fn check),
+            explain => primitive!(2, async fn explain),
+            complete => primitive!(2, async fn complete),
+            hover => primitive!(2, async fn hover),
+            signature_help => primitive!(2, async fn signature_help),
+            format_expr => primitive!(2, async fn format_expr),
+            create_session => primitive!(2, async fn create_session),
+            session_eval => primitive!(3, async fn session_eval),
+            delete_session => primitive!(3, "delete_session", |b: &TryBackend, p: &str, _: &str| {
+                b.delete_session(p)
+            }),
+            metrics => primitive!(1, "metrics", |_: ()| to_json(&metrics::snapshot()))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_id_from_path() {
+        assert_eq!(session_id("/try/session/abc/eval"), "abc");
+        assert_eq!(session_id("/try/session/abc"), "abc");
+        assert_eq!(session_id("/try/eval"), "");
+    }
+
+    #[test]
+    fn gluon_version_from_lock_file() {
+        let lock_file: toml::Value = toml::from_str(
+            r#"
+            [[package]]
+            name = "gluon"
+            version = "0.18.3"
+            source = "git+https://github.com/gluon-lang/gluon#d7ce3e8179d0b5b3a9d8b9b9fa67f1a8b84e6a2c"
+
+            [[package]]
+            name = "gluon"
+            version = "0.18.3"
+            source = "registry+https://github.com/rust-lang/crates.io-index"
+
+            [[package]]
+            name = "gluon_crates_io"
+            version = "0.1.0"
+            dependencies = ["anyhow", "gluon 0.18.3 (registry+https://github.com/rust-lang/crates.io-index)"]
+
+            [[package]]
+            name = "gluon_master"
+            version = "0.1.0"
+            dependencies = ["gluon 0.18.3 (git+https://github.com/gluon-lang/gluon)", "try_gluon_api"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            gluon_version(&lock_file, "gluon_crates_io").as_deref(),
+            Some("0.18.3")
+        );
+        assert_eq!(
+            gluon_version(&lock_file, "gluon_master").as_deref(),
+            Some("d7ce3e")
+        );
+        assert_eq!(gluon_version(&lock_file, "gluon_unknown"), None);
+    }
+}
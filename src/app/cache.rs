@@ -0,0 +1,197 @@
+//! Caches the results of `eval` and `format_expr` as the examples and shared snippets are
+//! evaluated over and over with the same source.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::Mutex,
+};
+
+use try_gluon_api::{EvalRequest, EvalResult, EvalStats, Limits};
+
+use crate::backend::BackendInfo;
+
+/// Identifies a request to a specific version of a backend. The whole request is kept so that
+/// requests whose hashes collide never share an entry.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Key<R> {
+    backend: String,
+    version: String,
+    request: R,
+}
+
+impl<R> Key<R> {
+    pub fn new(info: &BackendInfo, request: R) -> Key<R> {
+        Key {
+            backend: info.name.clone(),
+            version: info.version.clone(),
+            request,
+        }
+    }
+}
+
+/// An evaluated request along with the limits it was evaluated with.
+pub type EvalKey = Key<(EvalRequest, Limits)>;
+
+/// The source of a formatted snippet.
+pub type FormatKey = Key<String>;
+
+/// A map which holds at most `capacity` entries, evicting the least recently used entry when it
+/// is full.
+#[derive(Debug)]
+struct Lru<K, V> {
+    capacity: usize,
+    /// Incremented on every access, the entry with the lowest tick is the least recently used
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    by_tick: BTreeMap<u64, K>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Lru<K, V> {
+    fn new(capacity: usize) -> Lru<K, V> {
+        Lru {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            by_tick: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (value, tick) = self.entries.get_mut(key)?;
+        self.by_tick.remove(tick);
+        *tick = self.tick;
+        self.by_tick.insert(self.tick, key.clone());
+        Some(value.clone())
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, tick)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.by_tick.remove(&tick);
+        }
+        self.by_tick.insert(self.tick, key);
+
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self
+                .by_tick
+                .pop_first()
+                .expect("Entries are also in `by_tick`");
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// The cached results of every backend. A capacity of 0 disables caching.
+#[derive(Debug)]
+pub struct Cache {
+    eval: Mutex<Lru<EvalKey, EvalResult>>,
+    format: Mutex<Lru<FormatKey, Result<String, String>>>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            eval: Mutex::new(Lru::new(capacity)),
+            format: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    /// The cached result of `key`, marked as `cached`. The timings are those of the evaluation
+    /// which was cached so they are left out.
+    pub fn get_eval(&self, key: &EvalKey) -> Option<EvalResult> {
+        let result = self.eval.lock().unwrap().get(key)?;
+        Some(EvalResult {
+            cached: true,
+            stats: result.stats.map(|stats| EvalStats {
+                parse_us: None,
+                typecheck_us: None,
+                compile_us: None,
+                execute_us: None,
+                ..stats
+            }),
+            ..result
+        })
+    }
+
+    /// Caches `result` if it is deterministic and completed within its limits.
+    pub fn insert_eval(&self, key: EvalKey, result: &EvalResult) {
+        if result.deterministic && result.limit_exceeded.is_none() {
+            self.eval.lock().unwrap().insert(key, result.clone());
+        }
+    }
+
+    pub fn get_format(&self, key: &FormatKey) -> Option<Result<String, String>> {
+        self.format.lock().unwrap().get(key)
+    }
+
+    pub fn insert_format(&self, key: FormatKey, result: Result<String, String>) {
+        self.format.lock().unwrap().insert(key, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(source: &str) -> EvalKey {
+        let info = BackendInfo {
+            name: "test".into(),
+            version: "0.0.0".into(),
+        };
+        let request = EvalRequest {
+            source: source.into(),
+            ..EvalRequest::default()
+        };
+        Key::new(&info, (request, Limits::default()))
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = Lru::new(2);
+        lru.insert(key("a"), 1);
+        lru.insert(key("b"), 2);
+        assert_eq!(lru.get(&key("a")), Some(1));
+
+        lru.insert(key("c"), 3);
+        assert_eq!(lru.get(&key("b")), None);
+        assert_eq!(lru.get(&key("a")), Some(1));
+        assert_eq!(lru.get(&key("c")), Some(3));
+
+        lru.insert(key("c"), 4);
+        assert_eq!(lru.get(&key("c")), Some(4));
+        assert_eq!(lru.entries.len(), lru.by_tick.len());
+    }
+
+    #[test]
+    fn only_deterministic_results_are_cached() {
+        let cache = Cache::new(8);
+        let stats = EvalStats {
+            execute_us: Some(10),
+            max_stack_depth: 1,
+            ..EvalStats::default()
+        };
+        let result = EvalResult {
+            deterministic: true,
+            stats: Some(stats),
+            ..EvalResult::success("1".into(), "Int".into())
+        };
+        cache.insert_eval(key("1"), &result);
+        let cached = EvalResult {
+            cached: true,
+            stats: Some(EvalStats {
+                execute_us: None,
+                ..stats
+            }),
+            ..result
+        };
+        assert_eq!(cache.get_eval(&key("1")), Some(cached));
+
+        cache.insert_eval(key("2"), &EvalResult::success("2".into(), "Int".into()));
+        assert_eq!(cache.get_eval(&key("2")), None);
+    }
+}
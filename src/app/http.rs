@@ -0,0 +1,144 @@
+//! Serves the routes of `server.glu` and the streaming endpoints when not running as a lambda
+//! function. Connections are accepted here rather than by `std.http.listen` as the rate limits
+//! need to know the address of each client.
+
+use std::{convert::Infallible, fs, net::SocketAddr};
+
+use {
+    anyhow::anyhow,
+    bytes::Bytes,
+    futures::prelude::*,
+    http_body_util::{combinators::BoxBody, BodyExt, Empty},
+    hyper::{
+        body::Incoming,
+        header::{self, HeaderValue},
+        server::conn::http1,
+        service::service_fn,
+        Request, Response, StatusCode,
+    },
+    hyper_util::rt::TokioIo,
+    tokio::{
+        io::{AsyncRead, AsyncWrite},
+        net::TcpListener,
+    },
+    tokio_native_tls::TlsAcceptor,
+};
+
+use gluon::std_lib::http::Handler;
+
+use crate::{
+    rate_limit::{self, RateLimits},
+    stream::Streams,
+    Result,
+};
+
+/// The certificate written by `setup_cert` in `server.glu`.
+const TLS_CERT: &str = "identity.p12";
+
+type Body = BoxBody<Bytes, Infallible>;
+
+/// Reads the certificate written by `setup_cert`.
+pub fn tls_acceptor() -> Result<TlsAcceptor> {
+    let der = fs::read(TLS_CERT)
+        .map_err(|err| anyhow!("Unable to read the certificate `{}`: {}", TLS_CERT, err))?;
+    // The certificate is exported without a password
+    let identity = native_tls::Identity::from_pkcs12(&der, "")?;
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+/// Accepts connections on `addr`, over TLS if `tls` is set, and responds with `streams` or
+/// `handler`.
+pub async fn serve(
+    addr: SocketAddr,
+    handler: Handler,
+    streams: Streams,
+    tls: Option<TlsAcceptor>,
+    rate_limits: RateLimits,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Listening on {}", addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (handler, streams, tls, rate_limits) = (
+            handler.clone(),
+            streams.clone(),
+            tls.clone(),
+            rate_limits.clone(),
+        );
+        tokio::spawn(async move {
+            let respond = move |request: Request<Incoming>| {
+                let client = rate_limits.client(Some(peer.ip()), request.headers());
+                if streams.serves(request.uri().path()) {
+                    streams.clone().handle(client, request).boxed()
+                } else {
+                    rate_limit::with_client(client, handle(handler.clone(), request)).boxed()
+                }
+            };
+            let result = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => serve_connection(stream, respond).await,
+                    Err(err) => Err(err.into()),
+                },
+                None => serve_connection(stream, respond).await,
+            };
+            if let Err(err) = result {
+                log::debug!("Connection with {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Redirects every request on `addr` to `https://<host>`.
+pub async fn redirect(addr: SocketAddr, host: String) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let location = HeaderValue::from_str(&format!("https://{}", host))?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let location = location.clone();
+        tokio::spawn(async move {
+            let respond = move |_| {
+                let response = Response::builder()
+                    .status(StatusCode::PERMANENT_REDIRECT)
+                    .header(header::LOCATION, location.clone())
+                    .body(Empty::new().boxed())
+                    .expect("Response is valid");
+                future::ready(response)
+            };
+            if let Err(err) = serve_connection(stream, respond).await {
+                log::debug!("Connection with {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Serves the requests of a single connection, responding to each with `respond`.
+async fn serve_connection<S, F, R>(stream: S, respond: F) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(Request<Incoming>) -> R + Send + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let service = service_fn(move |request| respond(request).map(Ok::<_, Infallible>));
+    http1::Builder::new()
+        .serve_connection(TokioIo::new(stream), service)
+        .await?;
+    Ok(())
+}
+
+async fn handle(mut handler: Handler, request: Request<Incoming>) -> Response<Body> {
+    let (parts, body) = request.into_parts();
+    match handler
+        .handle(parts.method, parts.uri, body.into_data_stream())
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            log::error!("{}", err);
+            let mut response = Response::new(Empty::new().boxed());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        }
+    }
+}
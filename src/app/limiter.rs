@@ -0,0 +1,212 @@
+//! Bounds the number of evaluations which run at once. Evaluations run on tokio's blocking
+//! threads so that they never occupy the threads serving other requests, and requests which
+//! arrive while every slot is taken wait in a bounded queue.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Returned when an evaluation could not be started as the server is overloaded.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Overloaded {
+    /// The number of seconds the client should wait before retrying
+    pub retry_after: u64,
+}
+
+/// A slot to run an evaluation in, which is given back when dropped.
+#[derive(Debug)]
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    /// `None` if the number of concurrent evaluations is not limited
+    slots: Option<Arc<Semaphore>>,
+    max_queued: usize,
+    queued: AtomicUsize,
+    queue_timeout: Duration,
+}
+
+impl Limiter {
+    /// Allows `max_running` evaluations at once (unlimited if 0) with at most `max_queued`
+    /// evaluations waiting for at most `queue_timeout` for a slot.
+    pub fn new(max_running: usize, max_queued: usize, queue_timeout: Duration) -> Limiter {
+        Limiter {
+            slots: if max_running == 0 {
+                None
+            } else {
+                Some(Arc::new(Semaphore::new(max_running)))
+            },
+            max_queued,
+            queued: AtomicUsize::new(0),
+            queue_timeout,
+        }
+    }
+
+    /// Runs `f` on a blocking thread once a slot is available. The slot is held until `f` returns,
+    /// even if the returned future is dropped before then.
+    pub async fn run<T>(&self, f: impl FnOnce() -> T + Send + 'static) -> Result<T, Overloaded>
+    where
+        T: Send + 'static,
+    {
+        let slot = self.acquire().await?;
+        Ok(tokio::task::spawn_blocking(move || {
+            let _slot = slot;
+            f()
+        })
+        .await
+        .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic())))
+    }
+
+    /// Waits for a slot, for callers which need to run the evaluation themselves.
+    pub async fn acquire(&self) -> Result<Slot, Overloaded> {
+        let permit = match &self.slots {
+            None => None,
+            Some(slots) => match slots.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => Some(self.wait(slots).await?),
+            },
+        };
+        Ok(Slot { _permit: permit })
+    }
+
+    async fn wait(&self, slots: &Arc<Semaphore>) -> Result<OwnedSemaphorePermit, Overloaded> {
+        let overloaded = Overloaded {
+            retry_after: self.queue_timeout.as_secs().max(1),
+        };
+
+        let place = Queued::new(&self.queued);
+        let queued = place.position;
+        if queued > self.max_queued {
+            log::warn!(
+                "Rejecting evaluation, {} evaluations are queued",
+                queued - 1
+            );
+            return Err(overloaded);
+        }
+
+        let start = Instant::now();
+        let permit = tokio::time::timeout(self.queue_timeout, slots.clone().acquire_owned()).await;
+        drop(place);
+
+        match permit {
+            Ok(Ok(permit)) => {
+                log::info!(
+                    "Evaluation waited {:?} for a slot with {} queued",
+                    start.elapsed(),
+                    queued
+                );
+                Ok(permit)
+            }
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => {
+                log::warn!(
+                    "Rejecting evaluation after waiting {:?} for a slot with {} queued",
+                    start.elapsed(),
+                    queued
+                );
+                Err(overloaded)
+            }
+        }
+    }
+}
+
+/// A place in the queue, which is left when dropped so that the queue also shrinks when the
+/// waiting request goes away.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    /// The number of evaluations queued, including this one, when it joined the queue
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn new(queued: &'a AtomicUsize) -> Queued<'a> {
+        Queued {
+            queued,
+            position: queued.fetch_add(1, Ordering::SeqCst) + 1,
+        }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{mpsc, Arc};
+
+    #[tokio::test]
+    async fn rejects_when_the_queue_is_full() {
+        let limiter = Arc::new(Limiter::new(1, 1, Duration::from_millis(200)));
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        // Occupies the only slot until `sender` is dropped
+        let running = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.run(move || receiver.recv().is_err()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Waits in the queue and times out
+        let queued = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.run(|| ()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(limiter.run(|| ()).await, Err(Overloaded { retry_after: 1 }));
+        assert_eq!(queued.await.unwrap(), Err(Overloaded { retry_after: 1 }));
+
+        drop(sender);
+        assert_eq!(running.await.unwrap(), Ok(true));
+        assert_eq!(limiter.run(|| 1).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn slot_is_held_after_the_caller_goes_away() {
+        let limiter = Arc::new(Limiter::new(1, 0, Duration::from_millis(50)));
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let running = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.run(move || receiver.recv()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Like a client disconnecting, the blocking work keeps running
+        running.abort();
+        let _ = running.await;
+
+        assert_eq!(limiter.run(|| ()).await, Err(Overloaded { retry_after: 1 }));
+        drop(sender);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(limiter.run(|| 1).await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn dropped_requests_leave_the_queue() {
+        let limiter = Limiter::new(1, 1, Duration::from_secs(60));
+        let slot = limiter.acquire().await.unwrap();
+
+        let mut waiting = Box::pin(limiter.acquire());
+        assert!(futures::poll!(&mut waiting).is_pending());
+        assert_eq!(limiter.queued.load(Ordering::SeqCst), 1);
+        // Like a client disconnecting while its request is queued
+        drop(waiting);
+        assert_eq!(limiter.queued.load(Ordering::SeqCst), 0);
+
+        drop(slot);
+        assert!(limiter.acquire().await.is_ok());
+    }
+}
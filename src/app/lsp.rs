@@ -0,0 +1,604 @@
+//! A language server which speaks LSP over a WebSocket (`/try/lsp` and `/try/<backend>/lsp`) so
+//! that editors embedded in the browser get diagnostics, completion, hover, go to definition and
+//! formatting from the sandboxed VMs.
+//!
+//! Each WebSocket message carries exactly one JSON-RPC message. Only full document
+//! synchronization is supported, the documents of a session live in memory until the socket is
+//! closed.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use {
+    futures::prelude::*,
+    serde_json::{json, Value},
+    tokio::{
+        net::{TcpListener, TcpStream},
+        sync::Semaphore,
+    },
+    tokio_tungstenite::tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::StatusCode,
+        Message,
+    },
+};
+
+use try_gluon_api::{CursorRequest, Position, SourceSpan};
+
+use crate::{
+    backend::{Backend, TryBackend},
+    rate_limit::RateLimits,
+    Result,
+};
+
+/// Bounds the resources the language server may use.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of sessions which may be connected at once
+    pub max_sessions: usize,
+    /// The number of documents a session may have open at once
+    pub max_documents: usize,
+    /// The size in bytes of the largest document a session may open
+    pub max_document_size: usize,
+    /// Sessions which do not send anything for this long are closed
+    pub idle_timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_sessions: 16,
+            max_documents: 16,
+            max_document_size: 256 * 1024,
+            idle_timeout: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// The route whose rate limit applies to the checks and queries of a session.
+const RATE_LIMIT_ROUTE: &str = "lsp";
+
+/// Accepts WebSocket connections on `addr`. `backends` maps the path of each endpoint to the
+/// backend it uses.
+pub async fn serve(
+    addr: SocketAddr,
+    backends: HashMap<String, TryBackend>,
+    config: Config,
+    rate_limits: RateLimits,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Language server listening on {}", addr);
+
+    let sessions = Arc::new(Semaphore::new(config.max_sessions));
+    let backends = Arc::new(backends);
+    let config = Arc::new(config);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let sessions = sessions.clone();
+        let backends = backends.clone();
+        let config = config.clone();
+        let rate_limits = rate_limits.clone();
+        tokio::spawn(async move {
+            let result = connect(stream, peer, &sessions, &backends, config, rate_limits).await;
+            if let Err(err) = result {
+                log::warn!("Language server session with {} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+// The size of `ErrorResponse` is decided by tungstenite
+#[allow(clippy::result_large_err)]
+async fn connect(
+    stream: TcpStream,
+    peer: SocketAddr,
+    sessions: &Arc<Semaphore>,
+    backends: &HashMap<String, TryBackend>,
+    config: Arc<Config>,
+    rate_limits: RateLimits,
+) -> Result<()> {
+    let permit = sessions.clone().try_acquire_owned().ok();
+
+    let mut backend = None;
+    let mut client = None;
+    let websocket =
+        tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response: Response| {
+            backend = backends.get(request.uri().path()).cloned();
+            client = rate_limits.client(Some(peer.ip()), request.headers());
+            if backend.is_none() {
+                Err(error_response(StatusCode::NOT_FOUND, "Unknown endpoint"))
+            } else if permit.is_none() {
+                Err(error_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many language server sessions",
+                ))
+            } else {
+                Ok(response)
+            }
+        })
+        .await?;
+    let backend = backend.expect("Backend is set by a successful handshake");
+
+    let mut session = Session {
+        backend,
+        config: config.clone(),
+        rate_limit: client.map(|client| (rate_limits, client)),
+        documents: HashMap::new(),
+    };
+    let (mut sink, mut stream) = websocket.split();
+    loop {
+        let message = match tokio::time::timeout(config.idle_timeout, stream.next()).await {
+            Ok(Some(message)) => message?,
+            Ok(None) => break,
+            Err(_) => {
+                log::debug!("Closing idle language server session");
+                break;
+            }
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let (replies, exit) = match serde_json::from_str(&text) {
+            Ok(message) => session.handle(message).await,
+            Err(err) => (
+                vec![error(Value::Null, PARSE_ERROR, &err.to_string())],
+                false,
+            ),
+        };
+        for reply in replies {
+            sink.send(Message::Text(reply.to_string())).await?;
+        }
+        if exit {
+            break;
+        }
+    }
+
+    // The session (and every document it holds) is dropped along with the permit here
+    sink.close().await.ok();
+    Ok(())
+}
+
+fn error_response(status: StatusCode, message: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(message.into()));
+    *response.status_mut() = status;
+    response
+}
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_PARAMS: i64 = -32602;
+const METHOD_NOT_FOUND: i64 = -32601;
+const REQUEST_FAILED: i64 = -32803;
+
+fn reply(id: Value, result: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "result": result })
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn show_error(message: &str) -> Value {
+    notification(
+        "window/showMessage",
+        json!({ "type": 1, "message": message }),
+    )
+}
+
+struct Session {
+    backend: TryBackend,
+    config: Arc<Config>,
+    /// The rate limits and the client they apply to, `None` if the client is not known
+    rate_limit: Option<(RateLimits, IpAddr)>,
+    /// The open documents, keyed by uri
+    documents: HashMap<String, String>,
+}
+
+impl Session {
+    /// Handles one message from the client, returning the messages to send back and whether the
+    /// session should end.
+    async fn handle(&mut self, message: Value) -> (Vec<Value>, bool) {
+        let id = message.get("id").cloned();
+        let method = message.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        let replies = match (method, id) {
+            ("initialize", Some(id)) => vec![reply(id, capabilities())],
+            ("shutdown", Some(id)) => vec![reply(id, Value::Null)],
+            ("exit", _) => return (Vec::new(), true),
+            ("textDocument/didOpen", None) => {
+                let document = &params["textDocument"];
+                match (document["uri"].as_str(), document["text"].as_str()) {
+                    (Some(uri), Some(text)) => self.update(uri, text).await,
+                    _ => Vec::new(),
+                }
+            }
+            ("textDocument/didChange", None) => {
+                let uri = params["textDocument"]["uri"].as_str();
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                match (uri, text) {
+                    (Some(uri), Some(text)) if self.documents.contains_key(uri) => {
+                        self.update(uri, text).await
+                    }
+                    _ => Vec::new(),
+                }
+            }
+            ("textDocument/didClose", None) => match params["textDocument"]["uri"].as_str() {
+                Some(uri) => {
+                    self.documents.remove(uri);
+                    vec![publish_diagnostics(uri, Vec::new())]
+                }
+                None => Vec::new(),
+            },
+            ("textDocument/completion", Some(id))
+            | ("textDocument/hover", Some(id))
+            | ("textDocument/signatureHelp", Some(id))
+            | ("textDocument/definition", Some(id)) => match self.cursor(&params) {
+                Some((uri, request)) => match self.query(method, uri, request).await {
+                    Ok(result) => vec![reply(id, result)],
+                    Err(message) => vec![error(id, REQUEST_FAILED, &message)],
+                },
+                None => vec![error(id, INVALID_PARAMS, "Unknown document or position")],
+            },
+            ("textDocument/formatting", Some(id)) => {
+                match params["textDocument"]["uri"]
+                    .as_str()
+                    .and_then(|uri| self.documents.get(uri))
+                {
+                    Some(source) => {
+                        let source = source.clone();
+                        let end = end_position(&source);
+                        let formatted = self.analysis(move |backend| backend.format(&source));
+                        match formatted.await {
+                            Ok(Ok(formatted)) => vec![reply(
+                                id,
+                                json!([{
+                                    "range": { "start": { "line": 0, "character": 0 }, "end": end },
+                                    "newText": formatted,
+                                }]),
+                            )],
+                            // Formatting fails on code which does not parse, the diagnostics
+                            // already tell the user why
+                            Ok(Err(_)) => vec![reply(id, json!([]))],
+                            Err(message) => vec![error(id, REQUEST_FAILED, &message)],
+                        }
+                    }
+                    None => vec![error(id, INVALID_PARAMS, "Unknown document")],
+                }
+            }
+            (_, Some(id)) => vec![error(id, METHOD_NOT_FOUND, "Method not found")],
+            // Unknown notifications are ignored as the protocol requires
+            (_, None) => Vec::new(),
+        };
+        (replies, false)
+    }
+
+    /// Stores the new contents of `uri` and checks it.
+    async fn update(&mut self, uri: &str, text: &str) -> Vec<Value> {
+        if text.len() > self.config.max_document_size {
+            self.documents.remove(uri);
+            return vec![show_error(&format!(
+                "`{}` is larger than the {} bytes the playground can check",
+                uri, self.config.max_document_size
+            ))];
+        }
+        if !self.documents.contains_key(uri) && self.documents.len() >= self.config.max_documents {
+            return vec![show_error(&format!(
+                "Only {} documents may be open at once",
+                self.config.max_documents
+            ))];
+        }
+        self.documents.insert(uri.into(), text.into());
+
+        let source = text.to_string();
+        let result = match self.analysis(move |backend| backend.check(&source)).await {
+            Ok(result) => result,
+            Err(message) => return vec![show_error(&message)],
+        };
+        let diagnostics = result
+            .diagnostics
+            .iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": diagnostic
+                        .span
+                        .map_or_else(empty_range, |span| range(text, span)),
+                    "severity": 1,
+                    "source": "gluon",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect();
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+
+    /// Looks up the document and cursor of a `TextDocumentPositionParams`.
+    fn cursor(&self, params: &Value) -> Option<(String, CursorRequest)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let source = self.documents.get(uri)?;
+        let position = &params["position"];
+        let offset = offset(
+            source,
+            position["line"].as_u64()?,
+            position["character"].as_u64()?,
+        );
+        Some((
+            uri.into(),
+            CursorRequest {
+                source: source.clone(),
+                offset,
+            },
+        ))
+    }
+
+    async fn query(
+        &self,
+        method: &str,
+        uri: String,
+        request: CursorRequest,
+    ) -> Result<Value, String> {
+        let source = request.source.clone();
+        Ok(match method {
+            "textDocument/completion" => {
+                let completions = self
+                    .analysis(move |backend| backend.complete(&request))
+                    .await?;
+                completions
+                    .items
+                    .into_iter()
+                    .map(|item| json!({ "label": item.name, "detail": item.typ }))
+                    .collect()
+            }
+            "textDocument/hover" => {
+                let hover = self
+                    .analysis(move |backend| backend.hover(&request))
+                    .await?;
+                match hover.typ {
+                    Some(typ) => {
+                        let mut contents = format!("```gluon\n{}\n```", typ);
+                        if let Some(doc) = hover.doc.filter(|doc| !doc.is_empty()) {
+                            contents.push_str("\n\n");
+                            contents.push_str(&doc);
+                        }
+                        json!({ "contents": { "kind": "markdown", "value": contents } })
+                    }
+                    None => Value::Null,
+                }
+            }
+            "textDocument/signatureHelp" => {
+                let help = self
+                    .analysis(move |backend| backend.signature_help(&request))
+                    .await?;
+                match help.signature {
+                    Some(signature) => json!({
+                        "signatures": [{
+                            "label": format!("{} : {}", signature.name, signature.typ),
+                        }],
+                        "activeSignature": 0,
+                        "activeParameter": signature.active_parameter,
+                    }),
+                    None => Value::Null,
+                }
+            }
+            "textDocument/definition" => {
+                let definition = self
+                    .analysis(move |backend| backend.definition(&request))
+                    .await?;
+                match definition.span {
+                    Some(span) => json!({ "uri": uri, "range": range(&source, span) }),
+                    None => Value::Null,
+                }
+            }
+            _ => unreachable!("Unexpected query {}", method),
+        })
+    }
+
+    /// Runs `f` through the limiter of the backend like the HTTP endpoints, after taking a request
+    /// from the client's rate limit. Fails with a message for the user if the client or the server
+    /// is overloaded or `f` panicked.
+    async fn analysis<T>(
+        &self,
+        f: impl FnOnce(&dyn Backend) -> T + Send + 'static,
+    ) -> Result<T, String>
+    where
+        T: Send + 'static,
+    {
+        if let Some((rate_limits, client)) = &self.rate_limit {
+            if let Some(seconds) = rate_limits.check_client(RATE_LIMIT_ROUTE, *client) {
+                return Err(format!(
+                    "Too many requests, try again in {} seconds",
+                    seconds
+                ));
+            }
+        }
+        match self.backend.analysis(f).await {
+            Ok(result) => result,
+            Err(overloaded) => Err(format!(
+                "The server is overloaded, try again in {} seconds",
+                overloaded.retry_after
+            )),
+        }
+    }
+}
+
+fn capabilities() -> Value {
+    json!({
+        "capabilities": {
+            // Full document synchronization
+            "textDocumentSync": 1,
+            "completionProvider": { "triggerCharacters": ["."] },
+            "hoverProvider": true,
+            "signatureHelpProvider": { "triggerCharacters": [" ", "("] },
+            "definitionProvider": true,
+            "documentFormattingProvider": true,
+        },
+        "serverInfo": { "name": "try_gluon" },
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    notification(
+        "textDocument/publishDiagnostics",
+        json!({ "uri": uri, "diagnostics": diagnostics }),
+    )
+}
+
+/// Converts an LSP position (0-based line and UTF-16 character) into a byte offset in `source`.
+fn offset(source: &str, line: u64, character: u64) -> usize {
+    let mut line_start = 0;
+    for _ in 0..line {
+        match source[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return source.len(),
+        }
+    }
+
+    let mut utf16 = 0;
+    for (i, c) in source[line_start..].char_indices() {
+        if c == '\n' || utf16 >= character {
+            return line_start + i;
+        }
+        utf16 += c.len_utf16() as u64;
+    }
+    source.len()
+}
+
+/// Converts a (1-based, counted in bytes) `Position` into an LSP position.
+fn lsp_position(source: &str, position: Position) -> Value {
+    let line = position.line.saturating_sub(1);
+    let text = source.split('\n').nth(line).unwrap_or("");
+    let mut column = position.column.saturating_sub(1).min(text.len());
+    while !text.is_char_boundary(column) {
+        column -= 1;
+    }
+    json!({ "line": line, "character": text[..column].encode_utf16().count() })
+}
+
+fn range(source: &str, span: SourceSpan) -> Value {
+    json!({
+        "start": lsp_position(source, span.start),
+        "end": lsp_position(source, span.end),
+    })
+}
+
+fn empty_range() -> Value {
+    json!({ "start": { "line": 0, "character": 0 }, "end": { "line": 0, "character": 0 } })
+}
+
+fn end_position(source: &str) -> Value {
+    let line = source.matches('\n').count();
+    let last_line = source.rsplit('\n').next().unwrap_or("");
+    json!({ "line": line, "character": last_line.encode_utf16().count() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::OnceLock;
+
+    fn session() -> Session {
+        static BACKEND: OnceLock<TryBackend> = OnceLock::new();
+        let backend = BACKEND.get_or_init(|| {
+            let vm = gluon_master::make_eval_vm(&gluon_master::SandboxPolicy::default()).unwrap();
+            TryBackend::for_tests(Arc::new(vm))
+        });
+        Session {
+            backend: backend.clone(),
+            config: Arc::new(Config::default()),
+            rate_limit: None,
+            documents: HashMap::new(),
+        }
+    }
+
+    fn open(uri: &str, text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": { "uri": uri, "languageId": "gluon", "version": 1, "text": text },
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn initialize() {
+        let message = json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} });
+        let (replies, exit) = session().handle(message).await;
+        assert!(!exit);
+        assert_eq!(replies.len(), 1, "{:?}", replies);
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+    }
+
+    #[tokio::test]
+    async fn opened_documents_are_checked() {
+        let mut session = session();
+        let (replies, _) = session
+            .handle(open("file:///a.glu", "let x : Int = \"\"\nx"))
+            .await;
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(replies[0]["params"]["uri"], "file:///a.glu");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 0);
+
+        let (replies, _) = session.handle(open("file:///b.glu", "let x = 1\nx")).await;
+        assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn complete_bindings() {
+        let mut session = session();
+        session
+            .handle(open("file:///a.glu", "let abc = 1\nabc"))
+            .await;
+        let (replies, _) = session
+            .handle(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "textDocument/completion",
+                "params": {
+                    "textDocument": { "uri": "file:///a.glu" },
+                    "position": { "line": 1, "character": 2 },
+                },
+            }))
+            .await;
+        assert_eq!(replies[0]["id"], 2);
+        let items = replies[0]["result"].as_array().unwrap();
+        assert!(
+            items.iter().any(|item| item["label"] == "abc"),
+            "{:?}",
+            items
+        );
+    }
+
+    #[test]
+    fn positions_count_utf16() {
+        let source = "let x = 1\nlet å𝔸 = x\nå𝔸";
+        assert_eq!(offset(source, 0, 4), 4);
+        assert_eq!(offset(source, 1, 4), source.find('å').unwrap());
+        // `𝔸` is two UTF-16 code units but four bytes
+        assert_eq!(offset(source, 1, 7), source.find(" = x").unwrap());
+        assert_eq!(offset(source, 5, 0), source.len());
+
+        assert_eq!(
+            lsp_position(source, Position { line: 3, column: 7 }),
+            json!({ "line": 2, "character": 3 })
+        );
+        assert_eq!(end_position(source), json!({ "line": 2, "character": 3 }));
+    }
+}
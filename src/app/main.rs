@@ -1,4 +1,4 @@
-use std::{convert::Infallible, fs, ops::Deref};
+use std::{collections::HashMap, convert::Infallible, fs, net::IpAddr};
 
 use {
     anyhow::anyhow,
@@ -12,75 +12,31 @@ use {
 
 use gluon_codegen::{Getable, Pushable, Trace, Userdata, VmType};
 
+use try_gluon_api::Limits;
+
 use gluon::{
     vm::{
-        self,
-        api::{Function, OwnedFunction, RuntimeResult, IO},
+        api::{Function, IO},
         primitive, record, ExternModule,
     },
-    RootedThread, Thread, ThreadExt,
+    RootedThread, ThreadExt,
 };
 
-type Error = anyhow::Error;
-type Result<T, E = Error> = std::result::Result<T, E>;
-
-pub fn load_master(thread: &Thread) -> vm::Result<ExternModule> {
-    #[derive(Debug, VmType, Userdata, Trace, Clone)]
-    #[gluon(vm_type = "MasterTryThread")]
-    #[gluon_userdata(clone)]
-    #[gluon_trace(skip)]
-    pub struct TryThread(gluon_master::RootedThread);
-
-    impl Deref for TryThread {
-        type Target = gluon_master::Thread;
-
-        fn deref(&self) -> &Self::Target {
-            &self.0
-        }
-    }
-
-    thread.register_type::<TryThread>("MasterTryThread", &[])?;
-
-    ExternModule::new(
-        thread,
-        record! {
-            make_eval_vm => primitive!(1, "make_eval_vm", |()| {
-                RuntimeResult::from(gluon_master::make_eval_vm().map(TryThread))
-            }),
-            eval => primitive!(2, "eval", |t: &TryThread, s: &str| gluon_master::eval(t, s)),
-            format_expr => primitive!(2, |t: &TryThread, s: &str| gluon_master::format_expr(t, s))
-        },
-    )
-}
-
-pub fn load(thread: &Thread) -> vm::Result<ExternModule> {
-    #[derive(Debug, VmType, Userdata, Trace, Clone)]
-    #[gluon(vm_type = "TryThread")]
-    #[gluon_userdata(clone)]
-    #[gluon_trace(skip)]
-    pub struct TryThread(gluon_crates_io::RootedThread);
+use crate::{backend::Registry, rate_limit::RateLimits};
 
-    impl Deref for TryThread {
-        type Target = gluon_crates_io::Thread;
+mod backend;
+mod cache;
+mod http;
+mod limiter;
+mod lsp;
+mod metrics;
+mod rate_limit;
+mod session;
+mod stream;
+mod worker;
 
-        fn deref(&self) -> &Self::Target {
-            &self.0
-        }
-    }
-
-    thread.register_type::<TryThread>("TryThread", &[])?;
-
-    ExternModule::new(
-        thread,
-        record! {
-            make_eval_vm => primitive!(1, "make_eval_vm", |()| {
-                RuntimeResult::from(gluon_crates_io::make_eval_vm().map(TryThread))
-            }),
-            eval => primitive!(2, "eval", |t: &TryThread, s: &str| gluon_crates_io::eval(t, s)),
-            format_expr => primitive!(2, |t: &TryThread, s: &str| gluon_crates_io::format_expr(t, s))
-        },
-    )
-}
+type Error = anyhow::Error;
+type Result<T, E = Error> = std::result::Result<T, E>;
 
 #[derive(Debug, Default, Getable, VmType)]
 pub struct Gist<'a> {
@@ -153,7 +109,7 @@ async fn exit_server() -> Result<()> {
     Ok(tokio::signal::ctrl_c().await?)
 }
 
-#[derive(Default, Parser, Pushable, VmType)]
+#[derive(Clone, Default, Parser, Pushable, VmType)]
 struct Opts {
     #[arg(
         long = "gist-access-token",
@@ -189,6 +145,229 @@ struct Opts {
         action = clap::ArgAction::SetFalse
     )]
     lambda: bool,
+
+    #[arg(
+        long = "lsp-port",
+        env = "LSP_PORT",
+        help = "The port to serve the language server on (over WebSockets), only available when \
+                not running as a lambda function"
+    )]
+    lsp_port: Option<u16>,
+    #[arg(
+        long = "lsp-max-sessions",
+        env = "LSP_MAX_SESSIONS",
+        default_value_t = lsp::Config::default().max_sessions,
+        help = "The number of language server sessions which may be open at once"
+    )]
+    lsp_max_sessions: usize,
+
+    #[arg(
+        long = "backends",
+        env = "TRY_BACKENDS",
+        value_delimiter = ',',
+        help = "The gluon versions to serve (`released`, `master`), the first one is used when no \
+                version is selected. Defaults to every version"
+    )]
+    backends: Vec<String>,
+
+    #[arg(
+        long = "workers",
+        env = "EVAL_WORKERS",
+        default_value_t = 0,
+        help = "The number of worker processes (per backend) to evaluate snippets in. Snippets are \
+                evaluated in the server process if this is 0"
+    )]
+    workers: usize,
+    #[arg(
+        long = "worker",
+        hide = true,
+        help = "Runs as a worker process for the given backend (`master` or `released`)"
+    )]
+    worker: Option<String>,
+    #[arg(
+        long = "thread-pool-size",
+        env = "EVAL_THREAD_POOL_SIZE",
+        default_value_t = 4,
+        help = "The number of threads (per backend) which are kept ready to evaluate snippets on"
+    )]
+    thread_pool_size: usize,
+    #[arg(
+        long = "prelude-cache",
+        env = "PRELUDE_CACHE",
+        help = "The directory the compiled std prelude of each backend is kept in, so that it is \
+                only compiled on the first start of each version"
+    )]
+    prelude_cache: Option<String>,
+    #[arg(
+        long = "build-prelude-cache",
+        requires = "prelude_cache",
+        help = "Writes the prelude cache of every backend and exits"
+    )]
+    build_prelude_cache: bool,
+
+    #[arg(
+        long = "max-sessions",
+        env = "MAX_SESSIONS",
+        default_value_t = 32,
+        help = "The number of REPL sessions which may be open at once"
+    )]
+    max_sessions: usize,
+    #[arg(
+        long = "session-timeout",
+        env = "SESSION_TIMEOUT",
+        default_value_t = 600,
+        help = "The number of seconds a REPL session is kept after it was last used"
+    )]
+    session_timeout: u64,
+
+    #[arg(
+        long = "cache-size",
+        env = "EVAL_CACHE_SIZE",
+        default_value_t = 256,
+        help = "The number of evaluation and formatting results to cache (per kind), 0 disables \
+                the cache"
+    )]
+    cache_size: usize,
+
+    #[arg(
+        long = "max-concurrent-evals",
+        env = "MAX_CONCURRENT_EVALS",
+        default_value_t = std::thread::available_parallelism().map_or(1, |n| n.get()),
+        help = "The number of evaluations and formatting requests which may run at once, 0 \
+                removes the limit. Defaults to the number of CPUs"
+    )]
+    max_concurrent_evals: usize,
+    #[arg(
+        long = "eval-queue-size",
+        env = "EVAL_QUEUE_SIZE",
+        default_value_t = 64,
+        help = "The number of evaluations which may wait for a slot before the server responds \
+                with 503"
+    )]
+    eval_queue_size: usize,
+    #[arg(
+        long = "eval-queue-timeout",
+        env = "EVAL_QUEUE_TIMEOUT",
+        default_value_t = 5_000,
+        help = "The number of milliseconds an evaluation may wait for a slot before the server \
+                responds with 503"
+    )]
+    eval_queue_timeout: u64,
+
+    #[arg(
+        long = "eval-rate-limit",
+        env = "EVAL_RATE_LIMIT",
+        default_value_t = 60,
+        help = "The number of evaluations a client may make per minute, 0 removes the limit"
+    )]
+    eval_rate_limit: u32,
+    #[arg(
+        long = "format-rate-limit",
+        env = "FORMAT_RATE_LIMIT",
+        default_value_t = 60,
+        help = "The number of formatting requests a client may make per minute, 0 removes the \
+                limit"
+    )]
+    format_rate_limit: u32,
+    #[arg(
+        long = "share-rate-limit",
+        env = "SHARE_RATE_LIMIT",
+        default_value_t = 5,
+        help = "The number of gists a client may create per minute, 0 removes the limit"
+    )]
+    share_rate_limit: u32,
+    #[arg(
+        long = "session-rate-limit",
+        env = "SESSION_RATE_LIMIT",
+        default_value_t = 10,
+        help = "The number of REPL sessions a client may create per minute, 0 removes the limit"
+    )]
+    session_rate_limit: u32,
+    #[arg(
+        long = "lsp-rate-limit",
+        env = "LSP_RATE_LIMIT",
+        default_value_t = 300,
+        help = "The number of documents or queries a client may check through the language \
+                server per minute, 0 removes the limit"
+    )]
+    lsp_rate_limit: u32,
+    #[arg(
+        long = "trusted-proxies",
+        env = "TRUSTED_PROXIES",
+        value_delimiter = ',',
+        help = "The addresses of proxies whose `X-Forwarded-For` header is trusted to identify \
+                the client"
+    )]
+    trusted_proxies: Vec<String>,
+
+    #[command(flatten)]
+    limits: LimitOpts,
+}
+
+/// The maximum resources an evaluation may use. Requests may ask for lower limits but never for
+/// higher ones.
+#[derive(Clone, clap::Args, Pushable, VmType)]
+struct LimitOpts {
+    #[arg(
+        long = "memory-limit",
+        env = "EVAL_MEMORY_LIMIT",
+        default_value_t = Limits::default().memory,
+        help = "The number of bytes an evaluation may allocate"
+    )]
+    memory_limit: usize,
+    #[arg(
+        long = "stack-limit",
+        env = "EVAL_STACK_LIMIT",
+        default_value_t = Limits::default().stack,
+        help = "The maximum stack size of an evaluation"
+    )]
+    stack_limit: usize,
+    #[arg(
+        long = "fuel-limit",
+        env = "EVAL_FUEL_LIMIT",
+        default_value_t = Limits::default().fuel,
+        help = "The number of steps (calls and lines executed) an evaluation may take"
+    )]
+    fuel_limit: u64,
+    #[arg(
+        long = "output-limit",
+        env = "EVAL_OUTPUT_LIMIT",
+        default_value_t = Limits::default().output,
+        help = "The number of bytes an evaluation may print"
+    )]
+    output_limit: usize,
+    #[arg(
+        long = "time-limit",
+        env = "EVAL_TIME_LIMIT",
+        default_value_t = Limits::default().time_ms,
+        help = "The number of milliseconds an evaluation may run for, regardless of its fuel"
+    )]
+    time_limit: u64,
+}
+
+impl Default for LimitOpts {
+    fn default() -> Self {
+        let limits = Limits::default();
+        LimitOpts {
+            memory_limit: limits.memory,
+            stack_limit: limits.stack,
+            fuel_limit: limits.fuel,
+            output_limit: limits.output,
+            time_limit: limits.time_ms,
+        }
+    }
+}
+
+impl LimitOpts {
+    fn to_limits(&self) -> Limits {
+        Limits {
+            memory: self.memory_limit,
+            stack: self.stack_limit,
+            fuel: self.fuel_limit,
+            output: self.output_limit,
+            time_ms: self.time_limit,
+        }
+    }
 }
 
 #[tokio::main]
@@ -197,6 +376,22 @@ async fn main() {
 
     let opts = Opts::parse();
 
+    if let Some(backend) = &opts.worker {
+        if let Err(err) = worker::run(backend, opts.prelude_cache.as_deref()) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if opts.build_prelude_cache {
+        // The cache is written as the backends are loaded
+        if let Err(err) = Registry::new(&opts) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let result = async {
         if opts.lambda {
             let handler = mk_handler(opts).await?;
@@ -227,7 +422,25 @@ async fn mk_handler(
         >,
     >,
 > {
-    let vm = new_vm().await;
+    let rate_limits = RateLimits::new(&opts)?;
+    let vm = new_vm(Registry::new(&opts)?, rate_limits.clone()).await?;
+    let handler = load_handler(&vm, opts).await?;
+
+    Ok(move |req| {
+        let handler = handler.clone();
+        let client = client_addr(&req, &rate_limits);
+        rate_limit::with_client(client, handler_fn(handler, req))
+            .inspect_err(|err| log::error!("{}", err))
+            .map_err(|err| Diagnostic {
+                error_type: "HandlerError".into(),
+                error_message: err.to_string(),
+            })
+            .boxed()
+    })
+}
+
+/// Loads `server.glu` and creates the handler of its routes.
+async fn load_handler(vm: &RootedThread, opts: Opts) -> Result<gluon::std_lib::http::Handler> {
     let server_source = fs::read_to_string("src/app/server.glu")?;
 
     vm.load_script_async("src.app.server", &server_source)
@@ -240,18 +453,17 @@ async fn mk_handler(
         .into_result()
         .map_err(|err| anyhow!(err))?;
 
-    let handler = gluon::std_lib::http::Handler::new(&vm, h);
+    Ok(gluon::std_lib::http::Handler::new(vm, h))
+}
 
-    Ok(move |req| {
-        let handler = handler.clone();
-        handler_fn(handler, req)
-            .inspect_err(|err| log::error!("{}", err))
-            .map_err(|err| Diagnostic {
-                error_type: "HandlerError".into(),
-                error_message: err.to_string(),
-            })
-            .boxed()
-    })
+fn client_addr(req: &lambda_http::Request, rate_limits: &RateLimits) -> Option<IpAddr> {
+    use lambda_http::{request::RequestContext, RequestExt};
+
+    let source_ip = match req.request_context_ref() {
+        Some(RequestContext::ApiGatewayV2(context)) => context.http.source_ip.as_deref(),
+        _ => None,
+    };
+    rate_limits.client(source_ip.and_then(|ip| ip.parse().ok()), req.headers())
 }
 
 async fn handler_fn(
@@ -273,10 +485,14 @@ async fn handler_fn(
     Ok(response)
 }
 
-async fn new_vm() -> RootedThread {
+async fn new_vm(registry: Registry, rate_limits: RateLimits) -> Result<RootedThread> {
     let vm = gluon::new_vm_async().await;
-    gluon::import::add_extern_module(&vm, "gluon.try", load);
-    gluon::import::add_extern_module(&vm, "gluon.try.master", load_master);
+    gluon::import::add_extern_module(&vm, "gluon.try", move |vm| {
+        backend::load_module(vm, &registry)
+    });
+    gluon::import::add_extern_module(&vm, "gluon.rate_limit.prim", move |vm| {
+        rate_limit::load_module(vm, &rate_limits)
+    });
     gluon::import::add_extern_module(&vm, "gluon.http_server", |vm| {
         ExternModule::new(
             vm,
@@ -313,22 +529,73 @@ async fn new_vm() -> RootedThread {
             },
         )
     });
+    vm.load_script_async("gluon.rate_limit", rate_limit::RATE_LIMIT)
+        .await?;
+
+    Ok(vm)
+}
+
+async fn start_language_server(
+    opts: &Opts,
+    registry: &Registry,
+    rate_limits: &RateLimits,
+    port: u16,
+) -> Result<()> {
+    let mut backends = HashMap::new();
+    backends.insert("/try/lsp".to_string(), registry.default_backend().clone());
+    for backend in &registry.backends {
+        backends.insert(format!("/try/{}/lsp", backend.info.name), backend.clone());
+    }
 
-    vm
+    let config = lsp::Config {
+        max_sessions: opts.lsp_max_sessions,
+        ..lsp::Config::default()
+    };
+    tokio::spawn(
+        lsp::serve(
+            ([0, 0, 0, 0], port).into(),
+            backends,
+            config,
+            rate_limits.clone(),
+        )
+        .inspect_err(|err| log::error!("Language server stopped: {}", err)),
+    );
+    Ok(())
+}
+
+/// The streaming eval endpoints of every backend.
+fn streams(registry: &Registry, rate_limits: &RateLimits) -> stream::Streams {
+    let mut backends = HashMap::new();
+    backends.insert(
+        "/try/eval/stream".to_string(),
+        registry.default_backend().clone(),
+    );
+    for backend in &registry.backends {
+        backends.insert(
+            format!("/try/{}/eval/stream", backend.info.name),
+            backend.clone(),
+        );
+    }
+    stream::Streams::new(backends, rate_limits.clone())
 }
 
 async fn main_(opts: Opts, quit: impl Future<Output = Result<()>>) -> Result<()> {
-    let vm = new_vm().await;
+    let registry = Registry::new(&opts)?;
+    let rate_limits = RateLimits::new(&opts)?;
 
-    let server_source = fs::read_to_string("src/app/server.glu")?;
+    // A lambda function can not hold on to a WebSocket so the language server only runs here
+    if let Some(port) = opts.lsp_port {
+        start_language_server(&opts, &registry, &rate_limits, port).await?;
+    }
+    // Nor can it stream a response, so streaming is routed in front of `server.glu`
+    let streams = streams(&registry, &rate_limits);
+
+    let vm = new_vm(registry, rate_limits.clone()).await?;
 
     future::try_select(
         Box::pin(async move {
-            vm.load_script_async("src.app.server", &server_source)
-                .await?;
-            let mut f: OwnedFunction<fn(Opts) -> IO<()>> = vm.get_global("src.app.server.start")?;
-            f.call_async(opts).await?;
-            Ok(())
+            let handler = load_handler(&vm, opts.clone()).await?;
+            serve(&vm, handler, streams, opts, rate_limits).await
         }),
         Box::pin(quit),
     )
@@ -340,6 +607,44 @@ async fn main_(opts: Opts, quit: impl Future<Output = Result<()>>) -> Result<()>
     Ok(())
 }
 
+/// Serves `handler` and `streams` on the port in `opts`. With `--https` a certificate is retrieved
+/// first and plain http requests are redirected to https.
+async fn serve(
+    vm: &RootedThread,
+    handler: gluon::std_lib::http::Handler,
+    streams: stream::Streams,
+    opts: Opts,
+    rate_limits: RateLimits,
+) -> Result<()> {
+    let port = opts.port.unwrap_or(if opts.https { 443 } else { 80 });
+    let tls = if opts.https {
+        let mut setup_cert: Function<RootedThread, fn(Opts) -> IO<()>> =
+            vm.get_global("src.app.server.setup_cert")?;
+        let host = opts.host.clone();
+        setup_cert
+            .call_async(opts)
+            .await?
+            .into_result()
+            .map_err(|err| anyhow!(err))?;
+
+        tokio::spawn(
+            http::redirect(([0, 0, 0, 0], 80).into(), host)
+                .inspect_err(|err| log::error!("Redirecting to https stopped: {}", err)),
+        );
+        Some(http::tls_acceptor()?)
+    } else {
+        None
+    };
+    http::serve(
+        ([0, 0, 0, 0], port).into(),
+        handler,
+        streams,
+        tls,
+        rate_limits,
+    )
+    .await
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
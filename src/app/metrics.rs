@@ -0,0 +1,59 @@
+//! Counters exposed at `/try/metrics` for monitoring.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+static INTERNAL_ERRORS: AtomicU64 = AtomicU64::new(0);
+static CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+static CACHE_MISSES: AtomicU64 = AtomicU64::new(0);
+static CANCELLED: AtomicU64 = AtomicU64::new(0);
+static THREAD_POOL_MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Records a panic in the compiler or VM of a backend.
+pub fn internal_error() {
+    INTERNAL_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records an evaluation or formatting request which was answered from the cache.
+pub fn cache_hit() {
+    CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records an evaluation or formatting request which was not in the cache.
+pub fn cache_miss() {
+    CACHE_MISSES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records an evaluation which was aborted as the client went away.
+pub fn cancelled() {
+    CANCELLED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Records an evaluation which had to create its thread as the backend's thread pool was empty.
+pub fn thread_pool_miss() {
+    THREAD_POOL_MISSES.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Serialize)]
+pub struct Metrics {
+    /// The number of evaluations or formatting requests which panicked since the server started
+    pub internal_errors: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// The number of evaluations which were aborted as the client went away
+    pub cancelled: u64,
+    /// The number of evaluations which did not get a thread from the thread pool, if this grows
+    /// the pool is too small for the load
+    pub thread_pool_misses: u64,
+}
+
+pub fn snapshot() -> Metrics {
+    Metrics {
+        internal_errors: INTERNAL_ERRORS.load(Ordering::Relaxed),
+        cache_hits: CACHE_HITS.load(Ordering::Relaxed),
+        cache_misses: CACHE_MISSES.load(Ordering::Relaxed),
+        cancelled: CANCELLED.load(Ordering::Relaxed),
+        thread_pool_misses: THREAD_POOL_MISSES.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,302 @@
+//! Per-client rate limiting of the routes which are expensive or have side effects outside of the
+//! server (`/try/share` creates gists), and of the checks run by the language server.
+//!
+//! Each client gets a token bucket per route which holds `limit` tokens and is refilled at
+//! `limit` tokens per minute. Clients are identified by the address the request came from, or the
+//! address a trusted proxy received it from. Requests from unknown clients are not limited.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use {anyhow::anyhow, hyper::HeaderMap};
+
+use gluon_codegen::{Trace, Userdata, VmType};
+
+use gluon::{
+    vm::{self, api::IO, primitive, record, ExternModule},
+    Thread,
+};
+
+use crate::{Opts, Result};
+
+/// The router side of the rate limiter, `limit route handler` responds with 429 instead of
+/// running `handler` when the client has used up its requests to `route`.
+pub const RATE_LIMIT: &str = r#"
+let { HttpEffect, Response, write_response } = import! std.http
+let { Eff, ? } = import! std.effect
+let { lift } = import! std.effect.lift
+let { wrap } = import! std.applicative
+let string = import! std.string
+let prim = import! gluon.rate_limit.prim
+
+let too_many_requests = 429
+
+let limit route handler : String -> Eff (HttpEffect r) Response -> Eff (HttpEffect r) Response =
+    do retry_after = lift (prim.check prim.limits route)
+    match retry_after with
+    | None -> handler
+    | Some seconds ->
+        seq write_response (string.as_bytes "Too many requests, try again later")
+        wrap
+            {
+                status = too_many_requests,
+                headers = [("Retry-After", string.as_bytes (show seconds))],
+            }
+
+{ limit }
+"#;
+
+/// The most buckets a route holds. Full buckets are removed first, then the least recently used.
+const MAX_BUCKETS: usize = 10_000;
+
+tokio::task_local! {
+    static CLIENT: Option<IpAddr>;
+}
+
+/// Runs `f` on behalf of `client`, the rate limits of the routes `f` handles apply to `client`.
+pub async fn with_client<F>(client: Option<IpAddr>, f: F) -> F::Output
+where
+    F: std::future::Future,
+{
+    CLIENT.scope(client, f).await
+}
+
+/// Finds the client which sent a request from `peer`. `X-Forwarded-For` is only trusted when the
+/// request came from one of `trusted_proxies`, in which case the last address added by an
+/// untrusted proxy is the client.
+pub fn client_addr(
+    peer: Option<IpAddr>,
+    forwarded_for: &[&str],
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    // Every proxy appends the address it received the request from
+    let forwarded = forwarded_for
+        .iter()
+        .rev()
+        .flat_map(|header| header.rsplit(','));
+    for addr in forwarded {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match addr.trim().parse() {
+            Ok(addr) => client = addr,
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The buckets of a single route.
+#[derive(Debug)]
+struct Route {
+    /// The number of requests per minute, 0 if the route is not limited
+    limit: u32,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl Route {
+    /// Takes a token from `client`'s bucket, returning the number of seconds until a token is
+    /// available if the bucket is empty.
+    fn check(&mut self, client: IpAddr, now: Instant) -> Option<u64> {
+        if self.limit == 0 {
+            return None;
+        }
+        let capacity = f64::from(self.limit);
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * capacity / 60.).min(capacity)
+        };
+
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&client) {
+            self.buckets.retain(|_, bucket| refill(bucket) < capacity);
+            // Every client of a flood from many addresses has spent a token, so the buckets
+            // which were used longest ago make room instead
+            if self.buckets.len() >= MAX_BUCKETS {
+                let mut updated: Vec<_> =
+                    self.buckets.values().map(|bucket| bucket.updated).collect();
+                let (_, &mut cutoff, _) = updated.select_nth_unstable(MAX_BUCKETS / 4);
+                self.buckets.retain(|_, bucket| bucket.updated > cutoff);
+            }
+        }
+
+        let bucket = self.buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            None
+        } else {
+            Some(((1. - bucket.tokens) * 60. / capacity).ceil() as u64)
+        }
+    }
+}
+
+/// The rate limits of every route, configured by `Opts`.
+#[derive(Clone, Debug, VmType, Userdata, Trace)]
+#[gluon(vm_type = "RateLimits")]
+#[gluon_userdata(clone)]
+#[gluon_trace(skip)]
+pub struct RateLimits {
+    routes: Arc<Mutex<HashMap<String, Route>>>,
+    /// The proxies whose `X-Forwarded-For` header identifies the client
+    trusted_proxies: Arc<Vec<IpAddr>>,
+}
+
+impl RateLimits {
+    pub fn new(opts: &Opts) -> Result<RateLimits> {
+        let routes = [
+            ("eval", opts.eval_rate_limit),
+            ("format", opts.format_rate_limit),
+            ("share", opts.share_rate_limit),
+            ("session", opts.session_rate_limit),
+            ("lsp", opts.lsp_rate_limit),
+        ];
+        let trusted_proxies = opts
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .map_err(|err| anyhow!("Invalid trusted proxy `{}`: {}", proxy, err))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RateLimits {
+            routes: Arc::new(Mutex::new(
+                routes
+                    .iter()
+                    .map(|&(route, limit)| {
+                        let buckets = HashMap::new();
+                        (route.to_string(), Route { limit, buckets })
+                    })
+                    .collect(),
+            )),
+            trusted_proxies: Arc::new(trusted_proxies),
+        })
+    }
+
+    /// Finds the client which sent a request with `headers` from `peer`, see `client_addr`.
+    pub fn client(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let forwarded_for: Vec<&str> = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        client_addr(peer, &forwarded_for, &self.trusted_proxies)
+    }
+
+    fn check(&self, route: &str) -> Option<u64> {
+        let client = CLIENT.try_with(|client| *client).ok().flatten()?;
+        self.check_client(route, client)
+    }
+
+    /// Takes a request to `route` from `client`, returning the number of seconds until the client
+    /// may try again if it has used up its requests.
+    pub fn check_client(&self, route: &str, client: IpAddr) -> Option<u64> {
+        let mut routes = self.routes.lock().unwrap();
+        match routes.get_mut(route) {
+            Some(route) => route.check(client, Instant::now()),
+            None => {
+                log::warn!("No rate limit is configured for `{}`", route);
+                None
+            }
+        }
+    }
+}
+
+pub fn load_module(thread: &Thread, limits: &RateLimits) -> vm::Result<ExternModule> {
+    thread.register_type::<RateLimits>("RateLimits", &[])?;
+
+    ExternModule::new(
+        thread,
+        record! {
+            limits => limits.clone(),
+            check => primitive!(2, "check", |limits: &RateLimits, route: &str| {
+                IO::Value(limits.check(route))
+            })
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_only_trusted_from_proxies() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let forwarded = ["1.1.1.1, 2.2.2.2", "10.0.0.2"];
+
+        assert_eq!(
+            client_addr(Some(ip("10.0.0.1")), &forwarded, &proxies),
+            Some(ip("2.2.2.2"))
+        );
+        assert_eq!(
+            client_addr(Some(ip("3.3.3.3")), &forwarded, &proxies),
+            Some(ip("3.3.3.3"))
+        );
+        assert_eq!(
+            client_addr(Some(ip("10.0.0.1")), &[], &proxies),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(client_addr(None, &forwarded, &proxies), None);
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut route = Route {
+            limit: 2,
+            buckets: HashMap::new(),
+        };
+        let (client, other) = (ip("1.1.1.1"), ip("2.2.2.2"));
+        let now = Instant::now();
+
+        assert_eq!(route.check(client, now), None);
+        assert_eq!(route.check(client, now), None);
+        assert_eq!(route.check(client, now), Some(30));
+        assert_eq!(route.check(other, now), None);
+
+        let later = now + Duration::from_secs(30);
+        assert_eq!(route.check(client, later), None);
+        assert_eq!(route.check(client, later), Some(30));
+    }
+
+    #[test]
+    fn buckets_are_bounded() {
+        let mut route = Route {
+            limit: 1,
+            buckets: HashMap::new(),
+        };
+        let start = Instant::now();
+        let client = |i: usize| IpAddr::from(std::net::Ipv4Addr::from(i as u32));
+
+        for i in 0..2 * MAX_BUCKETS {
+            let now = start + Duration::from_millis(i as u64);
+            assert_eq!(route.check(client(i), now), None);
+            assert!(route.buckets.len() <= MAX_BUCKETS);
+        }
+        // The most recent clients are still limited
+        let now = start + Duration::from_millis(2 * MAX_BUCKETS as u64);
+        assert!(route.check(client(2 * MAX_BUCKETS - 1), now).is_some());
+    }
+}
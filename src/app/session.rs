@@ -0,0 +1,182 @@
+//! REPL sessions which keep the bindings of earlier evaluations between requests.
+//!
+//! Each session owns a VM of its own so the number of sessions is capped and sessions which have
+//! not been used for a while are dropped the next time a session is created or looked up.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use try_gluon_api::{Cancellation, EvalRequest, EvalResult, Limits};
+
+use crate::backend::{self, BackendInfo, ReplSession};
+
+/// A session along with the backend it was created from.
+pub struct Session {
+    info: BackendInfo,
+    max_limits: Limits,
+    repl: Mutex<Box<dyn ReplSession>>,
+}
+
+impl Session {
+    pub fn new(info: BackendInfo, max_limits: Limits, repl: Box<dyn ReplSession>) -> Session {
+        Session {
+            info,
+            max_limits,
+            repl: Mutex::new(repl),
+        }
+    }
+
+    /// Evaluates `request` in the session. Evaluations in the same session run one at a time.
+    pub fn eval(&self, request: &EvalRequest, cancellation: &Cancellation) -> EvalResult {
+        let limits = self.max_limits.clamp(&request.limits);
+        let mut repl = self.repl.lock().unwrap();
+        backend::catching_panics(&self.info, || repl.eval(request, &limits, cancellation))
+    }
+}
+
+struct Entry {
+    session: Arc<Session>,
+    last_used: Instant,
+}
+
+/// The sessions of every backend, keyed by their id.
+pub struct Sessions {
+    max: usize,
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, Entry>>,
+    ids: RandomState,
+    counter: AtomicU64,
+}
+
+impl std::fmt::Debug for Sessions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sessions")
+            .field("max", &self.max)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+impl Sessions {
+    pub fn new(max: usize, idle_timeout: Duration) -> Sessions {
+        Sessions {
+            max,
+            idle_timeout,
+            sessions: Mutex::new(HashMap::new()),
+            ids: RandomState::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns `true` if no more sessions can be created. Checked before creating a session as
+    /// creating its VM is the expensive part.
+    pub fn is_full(&self) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.remove_expired(&mut sessions);
+        sessions.len() >= self.max
+    }
+
+    /// Stores `session`, returning its id or `None` if there are too many sessions already.
+    pub fn insert(&self, session: Session) -> Option<String> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.remove_expired(&mut sessions);
+        if sessions.len() >= self.max {
+            return None;
+        }
+
+        let id = self.new_id();
+        sessions.insert(
+            id.clone(),
+            Entry {
+                session: Arc::new(session),
+                last_used: Instant::now(),
+            },
+        );
+        Some(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        self.remove_expired(&mut sessions);
+        sessions.get_mut(id).map(|entry| {
+            entry.last_used = Instant::now();
+            entry.session.clone()
+        })
+    }
+
+    /// Removes the session `id`, returning `false` if it did not exist.
+    pub fn remove(&self, id: &str) -> bool {
+        self.sessions.lock().unwrap().remove(id).is_some()
+    }
+
+    fn remove_expired(&self, sessions: &mut HashMap<String, Entry>) {
+        sessions.retain(|_, entry| entry.last_used.elapsed() < self.idle_timeout);
+    }
+
+    /// Creates an id which can not be guessed from the ids of other sessions, so that a session
+    /// can only be used by whoever created it.
+    fn new_id(&self) -> String {
+        let count = self.counter.fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now();
+        let half = |i: u64| self.ids.hash_one((i, count, now));
+        format!("{:016x}{:016x}", half(0), half(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    impl ReplSession for Echo {
+        fn eval(&mut self, request: &EvalRequest, _: &Limits, _: &Cancellation) -> EvalResult {
+            EvalResult {
+                value: Some(request.source.clone()),
+                ..EvalResult::default()
+            }
+        }
+    }
+
+    fn session() -> Session {
+        let info = BackendInfo {
+            name: "test".into(),
+            version: "0.0.0".into(),
+        };
+        Session::new(info, Limits::default(), Box::new(Echo))
+    }
+
+    #[test]
+    fn sessions_are_capped_and_expire() {
+        let sessions = Sessions::new(2, Duration::from_millis(100));
+        let first = sessions.insert(session()).unwrap();
+        let second = sessions.insert(session()).unwrap();
+        assert_ne!(first, second);
+        assert!(sessions.is_full());
+        assert!(sessions.insert(session()).is_none());
+
+        assert!(sessions.remove(&first));
+        assert!(!sessions.remove(&first));
+        assert!(sessions.get(&first).is_none());
+        let request = EvalRequest {
+            source: "1".into(),
+            ..EvalRequest::default()
+        };
+        let result = sessions
+            .get(&second)
+            .unwrap()
+            .eval(&request, &Cancellation::default());
+        assert_eq!(result.value.as_deref(), Some("1"));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(sessions.get(&second).is_none());
+        assert!(!sessions.is_full());
+    }
+}
@@ -0,0 +1,175 @@
+//! Evaluation which streams its output as server-sent events (`/try/eval/stream` and
+//! `/try/<backend>/eval/stream`). The request is the same as for `/try/eval` but the response is
+//! a `text/event-stream` of `EvalEvent`s, with the output sent as soon as it is printed.
+//!
+//! The gluon router only sends a response once the handler has finished, so these endpoints are
+//! routed by `http::serve` before the requests reach `server.glu`. A lambda function can not
+//! stream its response either, so they only exist when running with `--no-lambda`. The
+//! playground keeps using `/try/eval`, which is served by both deployments. Evaluations count
+//! against the same rate limit as `/try/eval`.
+
+use std::{collections::HashMap, convert::Infallible, net::IpAddr, sync::Arc};
+
+use {
+    bytes::Bytes,
+    futures::prelude::*,
+    http_body_util::{combinators::BoxBody, BodyExt, Full, Limited, StreamBody},
+    hyper::{
+        body::{Frame, Incoming},
+        header::{self, HeaderValue},
+        Method, Request, Response, StatusCode,
+    },
+    tokio::sync::mpsc,
+};
+
+use try_gluon_api::{Cancellation, EvalEvent, EvalRequest, OutputChunk};
+
+use crate::{
+    backend::{CancelOnDrop, TryBackend},
+    rate_limit::RateLimits,
+};
+
+/// The size in bytes of the largest request which is accepted.
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+type Body = BoxBody<Bytes, Infallible>;
+
+/// The streaming endpoints.
+#[derive(Clone)]
+pub struct Streams {
+    /// The backend used by each endpoint, keyed by its path
+    backends: Arc<HashMap<String, TryBackend>>,
+    rate_limits: RateLimits,
+}
+
+impl Streams {
+    pub fn new(backends: HashMap<String, TryBackend>, rate_limits: RateLimits) -> Self {
+        Streams {
+            backends: Arc::new(backends),
+            rate_limits,
+        }
+    }
+
+    /// Whether `path` is one of the streaming endpoints.
+    pub fn serves(&self, path: &str) -> bool {
+        self.backends.contains_key(path)
+    }
+
+    /// Responds to `request` from `client`, which must be for one of the streaming endpoints.
+    pub async fn handle(
+        self,
+        client: Option<IpAddr>,
+        request: Request<Incoming>,
+    ) -> Response<Body> {
+        let backend = match self.backends.get(request.uri().path()) {
+            Some(backend) if request.method() == Method::POST => backend.clone(),
+            Some(_) => return error(StatusCode::METHOD_NOT_ALLOWED, "Expected a POST request"),
+            None => return error(StatusCode::NOT_FOUND, "Unknown endpoint"),
+        };
+        handle(backend, &self.rate_limits, client, request).await
+    }
+}
+
+async fn handle(
+    backend: TryBackend,
+    rate_limits: &RateLimits,
+    client: Option<IpAddr>,
+    request: Request<Incoming>,
+) -> Response<Body> {
+    if let Some(seconds) = client.and_then(|client| rate_limits.check_client("eval", client)) {
+        let message = "Too many requests, try again later";
+        return retry_later(StatusCode::TOO_MANY_REQUESTS, message, seconds);
+    }
+
+    let body = match Limited::new(request.into_body(), MAX_REQUEST_SIZE)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(err) => {
+            let message = format!("Unable to read the request: {}", err);
+            return error(StatusCode::BAD_REQUEST, message);
+        }
+    };
+    let request = match std::str::from_utf8(&body)
+        .map_err(|err| format!("Invalid eval request: {}", err))
+        .and_then(EvalRequest::parse)
+    {
+        Ok(request) => request,
+        Err(message) => return error(StatusCode::BAD_REQUEST, message),
+    };
+
+    // Cached results are sent at once, without waiting for a slot to evaluate in
+    if let Some(result) = backend.cached_eval(&request) {
+        let output = result.output.iter().cloned().map(EvalEvent::Output);
+        let events: Vec<_> = output.chain(EvalEvent::from_result(&result)).collect();
+        return event_stream(stream::iter(events));
+    }
+
+    let slot = match backend.acquire_slot().await {
+        Ok(slot) => slot,
+        Err(overloaded) => {
+            let message = "The server is overloaded, try again later";
+            return retry_later(
+                StatusCode::SERVICE_UNAVAILABLE,
+                message,
+                overloaded.retry_after,
+            );
+        }
+    };
+
+    // Unbounded as the output is bounded by `Limits::output` already
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let cancellation = Cancellation::default();
+    tokio::task::spawn_blocking({
+        let cancellation = cancellation.clone();
+        move || {
+            let _slot = slot;
+            let output = sender.clone();
+            let on_output = Box::new(move |chunk: &OutputChunk| {
+                // Fails once the client has gone away, which also cancels the evaluation
+                let _ = output.send(EvalEvent::Output(chunk.clone()));
+            });
+            let result = backend.eval_streaming(&request, &cancellation, on_output);
+            for event in EvalEvent::from_result(&result) {
+                let _ = sender.send(event);
+            }
+        }
+    });
+
+    // The body is dropped if the client goes away before the evaluation has finished
+    let cancel_on_drop = CancelOnDrop(cancellation);
+    let events = stream::unfold(
+        (receiver, cancel_on_drop),
+        |(mut receiver, cancel_on_drop)| async move {
+            let event = receiver.recv().await?;
+            Some((event, (receiver, cancel_on_drop)))
+        },
+    );
+    event_stream(events)
+}
+
+/// Responds with `events` as a `text/event-stream`.
+fn event_stream(events: impl Stream<Item = EvalEvent> + Send + Sync + 'static) -> Response<Body> {
+    let frames = events.map(|event| Ok(Frame::data(Bytes::from(event.to_sse()))));
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(BodyExt::boxed(StreamBody::new(frames)))
+        .expect("Response is valid")
+}
+
+/// An error response which asks the client to retry after `seconds`.
+fn retry_later(status: StatusCode, message: &str, seconds: u64) -> Response<Body> {
+    let mut response = error(status, message);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+fn error(status: StatusCode, message: impl Into<String>) -> Response<Body> {
+    let mut response = Response::new(Full::new(Bytes::from(message.into())).boxed());
+    *response.status_mut() = status;
+    response
+}
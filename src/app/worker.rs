@@ -0,0 +1,300 @@
+//! Runs evaluations in `try_gluon --worker <backend>` processes so that a panic or a native stack
+//! overflow in the VM only takes down a worker instead of the whole server.
+//!
+//! A worker writes `READY` on its stdout once its VM is created. After that the server writes one
+//! JSON encoded `WorkerRequest` per line to the worker's stdin and the worker answers each of them
+//! with a JSON encoded `EvalResult` on a line of its own.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use {
+    anyhow::anyhow,
+    serde::{Deserialize, Serialize},
+};
+
+use try_gluon_api::{
+    Cancellation, Diagnostic, EvalRequest, EvalResult, LimitExceeded, LimitKind, Limits, Phase,
+};
+
+use crate::{backend, Result};
+
+const READY: &str = "READY";
+
+/// How long a worker may take to create its VM.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The time a worker gets on top of the evaluation's own time limit before it is killed. The
+/// worker enforces the time limit itself so this is only reached if the VM stops responding.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(5);
+
+/// How often a cancelled evaluation is noticed, at which point its worker is killed.
+const CANCELLATION_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Serialize, Deserialize)]
+struct WorkerRequest {
+    request: EvalRequest,
+    limits: Limits,
+}
+
+/// Runs a worker for `backend`, evaluating requests from stdin until it is closed.
+pub fn run(backend: &str, prelude_cache: Option<&str>) -> Result<()> {
+    let info = backend::info(backend)?;
+    let options = backend::LoadOptions {
+        // A single thread is enough as a worker only evaluates one request at a time
+        pool_size: 1,
+        prelude_cache: prelude_cache.map(Into::into),
+    };
+    let backend = backend::load(&info, &options)?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    writeln!(stdout, "{}", READY)?;
+    stdout.flush()?;
+
+    for line in io::stdin().lock().lines() {
+        let WorkerRequest { request, limits } = serde_json::from_str(&line?)?;
+        // Panics are caught here as well so that they are reported the same way as in the server.
+        // Cancelled evaluations are handled by killing the worker.
+        let cancellation = Cancellation::default();
+        let result =
+            backend::eval_catching_panics(&*backend, &info, &request, &limits, &cancellation);
+        serde_json::to_writer(&mut stdout, &result)?;
+        writeln!(stdout)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+enum Failure {
+    Crashed,
+    TimedOut,
+    Cancelled,
+}
+
+#[derive(Debug)]
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    lines: mpsc::Receiver<String>,
+}
+
+impl Worker {
+    fn spawn(backend: &str, prelude_cache: Option<&str>) -> Result<Worker> {
+        let mut command = Command::new(std::env::current_exe()?);
+        command.args(["--worker", backend]);
+        if let Some(prelude_cache) = prelude_cache {
+            command.args(["--prelude-cache", prelude_cache]);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+
+        // Read on a thread of its own so that a worker which never answers can be given up on
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(|line| line.ok()) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let worker = Worker {
+            child,
+            stdin,
+            lines,
+        };
+        match worker.lines.recv_timeout(STARTUP_TIMEOUT) {
+            Ok(line) if line == READY => Ok(worker),
+            _ => Err(anyhow!("The `{}` worker failed to start", backend)),
+        }
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    fn eval(
+        &mut self,
+        request: WorkerRequest,
+        timeout: Duration,
+        cancellation: &Cancellation,
+    ) -> Result<EvalResult, Failure> {
+        let mut line = serde_json::to_string(&request).map_err(|_| Failure::Crashed)?;
+        line.push('\n');
+        self.stdin
+            .write_all(line.as_bytes())
+            .and_then(|()| self.stdin.flush())
+            .map_err(|_| Failure::Crashed)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            if cancellation.is_cancelled() {
+                return Err(Failure::Cancelled);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(Failure::TimedOut);
+            }
+            match self
+                .lines
+                .recv_timeout(remaining.min(CANCELLATION_INTERVAL))
+            {
+                Ok(line) => return serde_json::from_str(&line).map_err(|_| Failure::Crashed),
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return Err(Failure::Crashed),
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A bounded set of workers for one backend. Workers are started on demand and a worker which
+/// crashes or times out is killed and replaced by a new one on the next evaluation.
+#[derive(Debug)]
+pub struct Pool {
+    backend: &'static str,
+    size: usize,
+    /// Passed on to the workers, see `LoadOptions::prelude_cache`
+    prelude_cache: Option<String>,
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    idle: Vec<Worker>,
+    /// The number of workers which are evaluating something or being started
+    busy: usize,
+}
+
+impl Pool {
+    pub fn new(backend: &'static str, size: usize, prelude_cache: Option<String>) -> Pool {
+        Pool {
+            backend,
+            size,
+            prelude_cache,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                busy: 0,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn eval(
+        &self,
+        request: &EvalRequest,
+        limits: &Limits,
+        cancellation: &Cancellation,
+    ) -> EvalResult {
+        let mut worker = match self.acquire() {
+            Ok(worker) => worker,
+            Err(err) => {
+                log::error!("{}", err);
+                return failure(Phase::Runtime, "Unable to start the sandbox");
+            }
+        };
+
+        let timeout = Duration::from_millis(limits.time_ms) + TIMEOUT_GRACE;
+        let request = WorkerRequest {
+            request: request.clone(),
+            limits: *limits,
+        };
+        match worker.eval(request, timeout, cancellation) {
+            Ok(result) => {
+                self.release(Some(worker));
+                result
+            }
+            Err(Failure::Crashed) => {
+                log::error!("The `{}` worker crashed", self.backend);
+                drop(worker);
+                self.release(None);
+                failure(
+                    Phase::Runtime,
+                    "The sandbox crashed while evaluating the snippet",
+                )
+            }
+            Err(Failure::TimedOut) => {
+                log::warn!("Killing unresponsive `{}` worker", self.backend);
+                drop(worker);
+                self.release(None);
+                EvalResult {
+                    limit_exceeded: Some(LimitExceeded {
+                        kind: LimitKind::Time,
+                        limit: limits.time_ms,
+                    }),
+                    ..failure(
+                        Phase::LimitExceeded,
+                        "The sandbox was killed after exceeding the time limit",
+                    )
+                }
+            }
+            Err(Failure::Cancelled) => {
+                // The worker may be in the middle of a long evaluation so it is cheaper to
+                // replace it than to wait for it
+                drop(worker);
+                self.release(None);
+                failure(Phase::Cancelled, "The evaluation was cancelled")
+            }
+        }
+    }
+
+    fn acquire(&self) -> Result<Worker> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while let Some(mut worker) = state.idle.pop() {
+                // Workers may die while idle (killed by the OS for instance)
+                if worker.is_alive() {
+                    state.busy += 1;
+                    return Ok(worker);
+                }
+            }
+            if state.busy < self.size {
+                state.busy += 1;
+                drop(state);
+                return Worker::spawn(self.backend, self.prelude_cache.as_deref())
+                    .inspect_err(|_| self.release(None));
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Returns a worker to the pool, `None` frees the slot of a worker that was killed.
+    fn release(&self, worker: Option<Worker>) {
+        let mut state = self.state.lock().unwrap();
+        state.busy -= 1;
+        state.idle.extend(worker);
+        self.available.notify_one();
+    }
+}
+
+fn failure(phase: Phase, message: &str) -> EvalResult {
+    EvalResult::failure(
+        phase,
+        vec![Diagnostic {
+            file: "<top>".into(),
+            span: None,
+            message: message.into(),
+        }],
+    )
+}
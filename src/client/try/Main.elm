@@ -442,7 +442,46 @@ prefixVersion model path =
 postEval : Model -> Cmd Msg
 postEval model =
     Http.send EvalDone <|
-        Http.post (prefixVersion model model.urls.eval) (Http.stringBody "text/plain" model.src) Json.string
+        Http.post (prefixVersion model model.urls.eval) (Http.stringBody "text/plain" model.src) decodeEvalResult
+
+
+decodeEvalResult : Json.Decoder String
+decodeEvalResult =
+    let
+        position =
+            Json.map2 (\line column -> String.fromInt line ++ ":" ++ String.fromInt column)
+                (Json.field "line" Json.int)
+                (Json.field "column" Json.int)
+
+        diagnostic =
+            Json.map3
+                (\file span message ->
+                    case span of
+                        Just start ->
+                            file ++ ":" ++ start ++ ": " ++ message
+
+                        Nothing ->
+                            file ++ ": " ++ message
+                )
+                (Json.field "file" Json.string)
+                (Json.field "span" (Json.nullable (Json.field "start" position)))
+                (Json.field "message" Json.string)
+
+        render output value typ diagnostics =
+            String.concat output
+                ++ (case ( value, typ ) of
+                        ( Just v, Just t ) ->
+                            v ++ " : " ++ t
+
+                        _ ->
+                            String.join "\n" diagnostics
+                   )
+    in
+        Json.map4 render
+            (Json.field "output" (Json.list (Json.field "text" Json.string)))
+            (Json.field "value" (Json.nullable Json.string))
+            (Json.field "type" (Json.nullable Json.string))
+            (Json.field "diagnostics" (Json.list diagnostic))
 
 
 postFormat : Model -> Cmd Msg
@@ -0,0 +1,664 @@
+//! Types shared between the web server and the gluon backends which make up the JSON API of
+//! try_gluon.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A request to evaluate a snippet.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EvalRequest {
+    pub source: String,
+    #[serde(default)]
+    pub limits: LimitOverrides,
+    /// The contents of the sandbox's virtual filesystem, keyed by path
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    /// Modules which `source` can import, keyed by file name (`data/list.glu` is imported as
+    /// `import! data.list`)
+    #[serde(default)]
+    pub modules: BTreeMap<String, String>,
+    /// Seeds the random number generator of `std.random`, a random seed is used if `None`
+    #[serde(default)]
+    pub seed: Option<u32>,
+    /// Also renders the value as a `ValueTree`, see `EvalResult::tree`
+    #[serde(default)]
+    pub tree: Option<TreeOptions>,
+    /// Also returns statistics about the evaluation, see `EvalResult::stats`
+    #[serde(default)]
+    pub stats: bool,
+}
+
+impl EvalRequest {
+    /// Parses a request body which is either a JSON encoded `EvalRequest` or the source code to
+    /// evaluate.
+    ///
+    /// A gluon record can never start with a quoted field name so a body starting with `{"` is
+    /// always treated as JSON.
+    pub fn parse(body: &str) -> Result<Self, String> {
+        if is_json_object(body) {
+            serde_json::from_str(body).map_err(|err| format!("Invalid eval request: {}", err))
+        } else {
+            Ok(EvalRequest {
+                source: body.into(),
+                ..EvalRequest::default()
+            })
+        }
+    }
+}
+
+fn is_json_object(body: &str) -> bool {
+    let mut chars = body.trim_start().chars();
+    chars.next() == Some('{') && chars.find(|c| !c.is_whitespace()) == Some('"')
+}
+
+/// The resources a single evaluation is allowed to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Limits {
+    /// The number of bytes the evaluating thread may allocate
+    pub memory: usize,
+    /// The maximum size of the stack
+    pub stack: usize,
+    /// The number of steps (function calls and executed lines) the evaluation may take
+    pub fuel: u64,
+    /// The number of bytes the evaluation may print, any output past this is discarded
+    pub output: usize,
+    /// The wall-clock time in milliseconds the evaluation may take. Only a backstop, `fuel` is
+    /// what normally stops a long running evaluation as it does not depend on the server's load
+    pub time_ms: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            memory: 2_000_000,
+            stack: 10_000,
+            fuel: 5_000_000,
+            output: 64 * 1024,
+            time_ms: 10_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Applies the limits requested by a client, never allowing them to exceed `self`.
+    pub fn clamp(&self, overrides: &LimitOverrides) -> Limits {
+        Limits {
+            memory: overrides.memory.map_or(self.memory, |x| x.min(self.memory)),
+            stack: overrides.stack.map_or(self.stack, |x| x.min(self.stack)),
+            fuel: overrides.fuel.map_or(self.fuel, |x| x.min(self.fuel)),
+            output: overrides.output.map_or(self.output, |x| x.min(self.output)),
+            time_ms: overrides
+                .time_ms
+                .map_or(self.time_ms, |x| x.min(self.time_ms)),
+        }
+    }
+}
+
+/// Limits requested by a client, any limit that is `None` uses the server's maximum.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LimitOverrides {
+    pub memory: Option<usize>,
+    pub stack: Option<usize>,
+    pub fuel: Option<u64>,
+    pub output: Option<usize>,
+    pub time_ms: Option<u64>,
+}
+
+/// How much of a value is rendered as a `ValueTree`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TreeOptions {
+    /// Values nested deeper than this are replaced by `ValueTree::Truncated`, the server renders
+    /// at most 64 levels
+    pub max_depth: usize,
+    /// The number of fields or elements shown of each record, variant and array, at most 1000
+    pub max_width: usize,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions {
+            max_depth: 6,
+            max_width: 100,
+        }
+    }
+}
+
+/// A value structured so that a client can display it without parsing gluon. Records, variants
+/// and arrays which are wider than `TreeOptions::max_width` only hold their first elements and
+/// count the `omitted` ones.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValueTree {
+    Int {
+        value: i64,
+    },
+    Float {
+        value: f64,
+    },
+    Byte {
+        value: u8,
+    },
+    Char {
+        value: char,
+    },
+    String {
+        value: String,
+    },
+    Record {
+        #[serde(rename = "type")]
+        typ: String,
+        fields: Vec<TreeField>,
+        omitted: usize,
+    },
+    Variant {
+        #[serde(rename = "type")]
+        typ: String,
+        constructor: String,
+        args: Vec<ValueTree>,
+        omitted: usize,
+    },
+    Array {
+        #[serde(rename = "type")]
+        typ: String,
+        elements: Vec<ValueTree>,
+        omitted: usize,
+    },
+    Function {
+        #[serde(rename = "type")]
+        typ: String,
+    },
+    /// A value which can not be inspected, such as a thread or a value defined in Rust
+    Opaque {
+        #[serde(rename = "type")]
+        typ: String,
+    },
+    /// A value nested deeper than `TreeOptions::max_depth`
+    Truncated {
+        #[serde(rename = "type")]
+        typ: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TreeField {
+    pub name: String,
+    pub value: ValueTree,
+}
+
+/// The result of evaluating a snippet.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EvalResult {
+    /// The printed value, present if the evaluation succeeded
+    pub value: Option<String>,
+    /// The type of the value, present if the evaluation succeeded
+    #[serde(rename = "type")]
+    pub typ: Option<String>,
+    /// The value as a tree, present if the evaluation succeeded and `EvalRequest::tree` was set
+    pub tree: Option<ValueTree>,
+    /// The phase which failed, present if the evaluation did not succeed
+    pub phase: Option<Phase>,
+    /// The limit which stopped the evaluation, if any
+    pub limit_exceeded: Option<LimitExceeded>,
+    /// The number of steps the evaluation took, see `Limits::fuel`
+    pub fuel_consumed: u64,
+    /// Everything printed to stdout and stderr, in the order it was printed
+    pub output: Vec<OutputChunk>,
+    /// Whether output was discarded due to exceeding `Limits::output`
+    pub output_truncated: bool,
+    /// Rich output emitted through `gluon.try.display`, in the order it was emitted
+    pub display: Vec<DisplayItem>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Whether evaluating the same request again gives the same result, only deterministic
+    /// results are cached
+    pub deterministic: bool,
+    /// Whether the result was served from the server's cache, in which case `stats` has no
+    /// timings
+    pub cached: bool,
+    /// The seed of the random number generator, evaluating again with this seed reproduces the
+    /// result. `None` if the snippet was never run
+    pub seed: Option<u32>,
+    /// Present if `EvalRequest::stats` was set
+    pub stats: Option<EvalStats>,
+}
+
+/// Where the time and memory of an evaluation went.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvalStats {
+    /// The time in microseconds spent parsing and expanding macros, which includes loading the
+    /// modules the snippet imports. Each duration is `None` if the phase was never reached or if
+    /// the result was served from the cache
+    pub parse_us: Option<u64>,
+    pub typecheck_us: Option<u64>,
+    pub compile_us: Option<u64>,
+    pub execute_us: Option<u64>,
+    /// The largest number of bytes the evaluating thread held at once, to compare against
+    /// `memory_limit`
+    pub memory: usize,
+    /// The number of bytes the thread was allowed to allocate, see `Limits::memory`
+    pub memory_limit: usize,
+    /// The deepest nesting of function calls the evaluation reached
+    pub max_stack_depth: usize,
+}
+
+impl EvalResult {
+    pub fn success(value: String, typ: String) -> Self {
+        EvalResult {
+            value: Some(value),
+            typ: Some(typ),
+            ..EvalResult::default()
+        }
+    }
+
+    pub fn failure(phase: Phase, diagnostics: Vec<Diagnostic>) -> Self {
+        EvalResult {
+            phase: Some(phase),
+            diagnostics,
+            ..EvalResult::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Parsing or macro expansion (including `import!`)
+    Parse,
+    Typecheck,
+    Compile,
+    Runtime,
+    LimitExceeded,
+    /// The compiler or VM panicked, this is always a bug in gluon
+    InternalError,
+    /// The evaluation was aborted as the client went away
+    Cancelled,
+}
+
+/// Lets the server abort an evaluation whose result is no longer wanted.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation(Arc<AtomicBool>);
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// An event sent by `/try/eval/stream` while a snippet is evaluated. Output is sent as it is
+/// printed, the remaining events are sent once the evaluation has finished with `done` last.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EvalEvent {
+    Output(OutputChunk),
+    Display(DisplayItem),
+    Diagnostic(Diagnostic),
+    LimitExceeded(LimitExceeded),
+    Value {
+        value: String,
+        #[serde(rename = "type")]
+        typ: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tree: Option<ValueTree>,
+    },
+    Done {
+        /// The phase which failed, `None` if the evaluation succeeded
+        phase: Option<Phase>,
+        fuel_consumed: u64,
+        output_truncated: bool,
+        seed: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stats: Option<EvalStats>,
+    },
+}
+
+impl EvalEvent {
+    /// The name of the server-sent event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvalEvent::Output(_) => "output",
+            EvalEvent::Display(_) => "display",
+            EvalEvent::Diagnostic(_) => "diagnostic",
+            EvalEvent::LimitExceeded(_) => "limit_exceeded",
+            EvalEvent::Value { .. } => "value",
+            EvalEvent::Done { .. } => "done",
+        }
+    }
+
+    /// The events which follow the output of an evaluation which returned `result`.
+    pub fn from_result(result: &EvalResult) -> Vec<EvalEvent> {
+        let mut events: Vec<_> = result
+            .display
+            .iter()
+            .cloned()
+            .map(EvalEvent::Display)
+            .collect();
+        let diagnostics = result.diagnostics.iter().cloned();
+        events.extend(diagnostics.map(EvalEvent::Diagnostic));
+        events.extend(result.limit_exceeded.map(EvalEvent::LimitExceeded));
+        if let (Some(value), Some(typ)) = (&result.value, &result.typ) {
+            events.push(EvalEvent::Value {
+                value: value.clone(),
+                typ: typ.clone(),
+                tree: result.tree.clone(),
+            });
+        }
+        events.push(EvalEvent::Done {
+            phase: result.phase,
+            fuel_consumed: result.fuel_consumed,
+            output_truncated: result.output_truncated,
+            seed: result.seed,
+            stats: result.stats,
+        });
+        events
+    }
+
+    /// Encodes the event in the `text/event-stream` format.
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).expect("Events can always be serialized");
+        format!("event: {}\ndata: {}\n\n", self.name(), data)
+    }
+}
+
+/// The result of typechecking a snippet without running it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CheckResult {
+    /// The inferred type, present if typechecking succeeded
+    #[serde(rename = "type")]
+    pub typ: Option<String>,
+    /// The phase which failed, present if typechecking did not succeed
+    pub phase: Option<Phase>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A request for the intermediate representations of a snippet (`/try/explain`).
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExplainRequest {
+    pub source: String,
+    /// The views to return, every view is returned if empty
+    #[serde(default)]
+    pub views: Vec<ExplainView>,
+}
+
+impl ExplainRequest {
+    pub fn wants(&self, view: ExplainView) -> bool {
+        self.views.is_empty() || self.views.contains(&view)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExplainView {
+    /// The source after macro expansion
+    Expanded,
+    /// The inferred type of every binding
+    Typed,
+    /// The bytecode of the top-level function
+    Bytecode,
+}
+
+/// The views of a snippet which were requested. A view is `None` if it was not requested or if
+/// the snippet failed to compile before it could be produced.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Explanation {
+    pub expanded: Option<String>,
+    /// The bindings in the order they appear in the source
+    pub typed: Option<Vec<TypedBinding>>,
+    /// One instruction per line
+    pub bytecode: Option<Vec<String>>,
+    /// The phase which failed, if any
+    pub phase: Option<Phase>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypedBinding {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub span: Option<SourceSpan>,
+}
+
+/// A request for information about the code at `offset` in `source`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CursorRequest {
+    pub source: String,
+    /// The byte offset of the cursor in `source`
+    pub offset: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Completions {
+    /// The completions found in the part of the snippet which could be typechecked
+    pub items: Vec<Completion>,
+    /// The errors in the snippet
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Completion {
+    pub name: String,
+    /// The type of the value or the kind of the type that would be completed
+    #[serde(rename = "type")]
+    pub typ: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Hover {
+    /// The type of the expression at the cursor
+    #[serde(rename = "type")]
+    pub typ: Option<String>,
+    /// The doc comment of the binding at the cursor
+    pub doc: Option<String>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SignatureHelp {
+    /// The function being called at the cursor
+    pub signature: Option<Signature>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signature {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub typ: String,
+    /// The index of the argument the cursor is at
+    pub active_parameter: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Definition {
+    /// Where the binding at the cursor is defined
+    pub span: Option<SourceSpan>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The response to creating a REPL session, the `id` is used to evaluate in it
+/// (`/try/session/<id>/eval`) and to delete it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NewSession {
+    pub id: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputChunk {
+    pub stream: Stream,
+    pub text: String,
+}
+
+/// Receives everything an evaluation prints as soon as it is printed.
+pub type OutputSink = Box<dyn FnMut(&OutputChunk)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// The MIME type of the tables emitted by `gluon.try.display`. The data is a JSON object with the
+/// names of the `columns` and the `rows`, each row is an array with a string for every column.
+pub const TABLE_MIME: &str = "application/vnd.gluon.table+json";
+
+/// Rich output such as HTML, SVG or a table which the client displays according to its MIME type.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DisplayItem {
+    pub mime: String,
+    pub data: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LimitExceeded {
+    pub kind: LimitKind,
+    /// The value of the limit that was exceeded
+    pub limit: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    Memory,
+    Stack,
+    Fuel,
+    Time,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub file: String,
+    /// The location of the error, if the error could be attributed to a specific part of the
+    /// source
+    pub span: Option<SourceSpan>,
+    pub message: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceSpan {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// A 1-based line and column (counted in bytes) in a source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plain_source() {
+        for source in &["1 + 2", "{ x = 1 }", "{}", "\"abc\""] {
+            assert_eq!(
+                EvalRequest::parse(source),
+                Ok(EvalRequest {
+                    source: source.to_string(),
+                    ..EvalRequest::default()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn parse_json_request() {
+        assert_eq!(
+            EvalRequest::parse(r#"{ "source": "1", "limits": { "time_ms": 100 } }"#),
+            Ok(EvalRequest {
+                source: "1".into(),
+                limits: LimitOverrides {
+                    time_ms: Some(100),
+                    ..LimitOverrides::default()
+                },
+                ..EvalRequest::default()
+            })
+        );
+        assert!(EvalRequest::parse(r#"{ "limits": {} }"#).is_err());
+    }
+
+    #[test]
+    fn eval_events() {
+        let result = EvalResult {
+            fuel_consumed: 3,
+            ..EvalResult::success("3".into(), "Int".into())
+        };
+        let events: Vec<_> = EvalEvent::from_result(&result)
+            .iter()
+            .map(EvalEvent::to_sse)
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "event: value\ndata: {\"value\":\"3\",\"type\":\"Int\"}\n\n",
+                "event: done\ndata: {\"phase\":null,\"fuel_consumed\":3,\"output_truncated\":false,\"seed\":null}\n\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn value_tree_json() {
+        let tree = ValueTree::Record {
+            typ: "{ x : Int }".into(),
+            fields: vec![TreeField {
+                name: "x".into(),
+                value: ValueTree::Int { value: 1 },
+            }],
+            omitted: 0,
+        };
+        assert_eq!(
+            serde_json::to_value(&tree).unwrap(),
+            serde_json::json!({
+                "kind": "record",
+                "type": "{ x : Int }",
+                "fields": [{ "name": "x", "value": { "kind": "int", "value": 1 } }],
+                "omitted": 0,
+            })
+        );
+        assert_eq!(
+            EvalRequest::parse(r#"{ "source": "1", "tree": { "max_depth": 2 } }"#)
+                .unwrap()
+                .tree,
+            Some(TreeOptions {
+                max_depth: 2,
+                ..TreeOptions::default()
+            })
+        );
+    }
+
+    #[test]
+    fn clamp_limits() {
+        let max = Limits::default();
+        let limits = max.clamp(&LimitOverrides {
+            memory: Some(max.memory * 2),
+            stack: Some(10),
+            fuel: Some(100),
+            output: None,
+            time_ms: None,
+        });
+        assert_eq!(
+            limits,
+            Limits {
+                memory: max.memory,
+                stack: 10,
+                fuel: 100,
+                output: max.output,
+                time_ms: max.time_ms,
+            }
+        );
+    }
+}